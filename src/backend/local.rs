@@ -0,0 +1,183 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::RouterBackend;
+use crate::config::{LocalReboot, Server};
+use crate::error::{Error, Result};
+use crate::probe::Diagnostic;
+use crate::procfs::{self, CpuTimes};
+use crate::remediation::Action;
+use crate::status::Status;
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Time between two `/proc/stat` samples when there is no earlier one
+pub(super) const CPU_SAMPLE: Duration = Duration::from_millis(500);
+
+/// Run a command, return its output or wrap its failure with `wrap`
+async fn run(command: &str, args: &[&str], wrap: fn(String) -> Error) -> Result<String> {
+    let output = Command::new(command)
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| wrap(format!("unable to run {}: {}", command, e)))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    if !output.status.success() {
+        return Err(wrap(format!(
+            "{} {} exited with {}: {}",
+            command,
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(stdout)
+}
+
+/// Read procfs of the machine the monitor runs on, usually the router itself
+pub struct Local {
+    proc_root: PathBuf,
+    reboot: LocalReboot,
+    cpu: Option<CpuTimes>,
+}
+
+impl Local {
+    pub fn new(server: &Server) -> anyhow::Result<Self> {
+        Ok(Self {
+            proc_root: PathBuf::from(&server.proc_root),
+            reboot: server.local_reboot,
+            cpu: None,
+        })
+    }
+
+    async fn read(&self, name: &str) -> Result<String> {
+        let path = self.proc_root.join(name);
+        tokio::fs::read_to_string(&path).await.map_err(|e| {
+            Error::UnexpectedStatus(format!("unable to read {}: {}", path.display(), e))
+        })
+    }
+
+    async fn cpu_usage(&mut self) -> Result<i32> {
+        let before = match self.cpu {
+            Some(before) => before,
+            None => {
+                let before = procfs::parse_stat(&self.read("stat").await?)?;
+                tokio::time::sleep(CPU_SAMPLE).await;
+                before
+            }
+        };
+        let now = procfs::parse_stat(&self.read("stat").await?)?;
+        self.cpu = Some(now);
+        Ok(now.usage_since(&before))
+    }
+}
+
+#[async_trait]
+impl RouterBackend for Local {
+    async fn login(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn fetch_status(&mut self) -> Result<Status> {
+        let cpu_usage = self.cpu_usage().await?;
+        let conntrack = self.read("sys/net/netfilter/nf_conntrack_count").await.ok();
+        procfs::parse_status(
+            Some(cpu_usage),
+            &self.read("loadavg").await?,
+            &self.read("meminfo").await?,
+            &self.read("uptime").await?,
+            conntrack.as_deref(),
+        )
+    }
+
+    async fn reboot(&mut self, dry_run: bool) -> Result<()> {
+        match self.reboot {
+            LocalReboot::Ubus if dry_run => {
+                run("ubus", &["list", "system"], Error::RebootRejected).await?;
+            }
+            LocalReboot::Ubus => {
+                run("ubus", &["call", "system", "reboot"], Error::RebootRejected).await?;
+            }
+            LocalReboot::Syscall => reboot_syscall(dry_run)?,
+        }
+        Ok(())
+    }
+
+    async fn remediate(&mut self, action: &Action, dry_run: bool) -> Result<()> {
+        let (object, method, args) = action.ubus_call();
+        if dry_run {
+            run("ubus", &["list", object], Error::RemediationFailed).await?;
+            return Ok(());
+        }
+        let args = args.to_string();
+        run(
+            "ubus",
+            &["call", object, method, &args],
+            Error::RemediationFailed,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn diagnose(&mut self, diagnostic: Diagnostic, target: &str) -> Result<String> {
+        let (command, args) = diagnostic.command(target);
+        let output = Command::new(command)
+            .args(&args)
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| Error::DiagnosticFailed(format!("unable to run {}: {}", command, e)))?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string()
+            + &String::from_utf8_lossy(&output.stderr))
+    }
+}
+
+/// Reboot with the reboot syscall, with `dry_run` only check the permission
+#[cfg(target_os = "linux")]
+fn reboot_syscall(dry_run: bool) -> Result<()> {
+    // SAFETY: geteuid, sync and reboot take no pointers
+    unsafe {
+        if dry_run {
+            if libc::geteuid() != 0 {
+                return Err(Error::RebootRejected(
+                    "reboot syscall needs root".to_string(),
+                ));
+            }
+            return Ok(());
+        }
+        libc::sync();
+        if libc::reboot(libc::RB_AUTOBOOT) != 0 {
+            return Err(Error::RebootRejected(
+                std::io::Error::last_os_error().to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reboot_syscall(_dry_run: bool) -> Result<()> {
+    Err(Error::RebootRejected(
+        "reboot syscall is only supported on Linux".to_string(),
+    ))
+}
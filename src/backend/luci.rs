@@ -0,0 +1,261 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::RouterBackend;
+use crate::config::Server;
+use crate::error::{Error, Result};
+use crate::get_current_timestamp;
+use crate::probe::Diagnostic;
+use crate::remediation::Action;
+use crate::status::{Status, LOAD_SCALE};
+use async_trait::async_trait;
+use regex::Regex;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+struct TokenField {
+    token: String,
+}
+
+impl TokenField {
+    fn new(token: String) -> Self {
+        Self { token }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct LuciLoginField {
+    luci_username: String,
+    luci_password: String,
+}
+
+impl From<&Server> for LuciLoginField {
+    fn from(server: &Server) -> Self {
+        Self {
+            luci_password: server.password.clone(),
+            luci_username: server.user.clone(),
+        }
+    }
+}
+
+fn parse_status(response: &Map<String, serde_json::Value>) -> Result<Status> {
+    let cpu_usage = match response.get("cpuusage") {
+        Some(serde_json::Value::String(cpu)) => {
+            let usage = cpu.split('\n').next().unwrap_or_default().trim();
+            usage.parse::<i32>().map_err(|_| {
+                Error::UnexpectedStatus(format!("cpuusage {:?} is not a number", cpu))
+            })?
+        }
+        _ => return Err(Error::UnexpectedStatus("no cpuusage field".to_string())),
+    };
+    let load_avg = match response.get("loadavg") {
+        Some(serde_json::Value::Array(load_avg)) => load_avg
+            .iter()
+            .map(|x| {
+                x.as_i64().map(|x| x as f64 / LOAD_SCALE).ok_or_else(|| {
+                    Error::UnexpectedStatus(format!("loadavg value {} is not an integer", x))
+                })
+            })
+            .collect::<Result<_>>()?,
+        _ => return Err(Error::UnexpectedStatus("no loadavg field".to_string())),
+    };
+    Ok(Status {
+        cpu_usage: Some(cpu_usage),
+        load_avg,
+        memory: response
+            .get("memory")
+            .and_then(|x| serde_json::from_value(x.clone()).ok()),
+        uptime: response.get("uptime").and_then(|x| x.as_u64()),
+        conntrack: response.get("conncount").and_then(|x| x.as_u64()),
+        extra: Default::default(),
+    })
+}
+
+/// LuCI renders the login form instead of the requested page when not logged in
+fn is_login_page(body: &str) -> bool {
+    body.contains("luci_password")
+}
+
+/// Scrape LuCI web interface
+pub struct Luci {
+    client: reqwest::Client,
+    host: String,
+    login_field: LuciLoginField,
+    token_exp: Regex,
+    /// The session cookie is also a ubus session
+    session: Option<String>,
+}
+
+impl Luci {
+    pub fn new(server: &Server) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .cookie_store(true)
+                .timeout(Duration::from_secs(server.timeout))
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+            host: server.get_host().clone(),
+            login_field: LuciLoginField::from(server),
+            token_exp: Regex::new(r"token: '(?P<token>[\da-f]{32})'")?,
+            session: None,
+        })
+    }
+}
+
+impl Luci {
+    /// Fetch a LuCI page and extract its CSRF token
+    async fn token(&self, page: &str) -> Result<String> {
+        let response = self
+            .client
+            .get(format!("{}/cgi-bin/luci/{}", self.host, page))
+            .send()
+            .await?;
+        if response.status() == StatusCode::FORBIDDEN {
+            return Err(Error::SessionExpired);
+        }
+        let response = response.error_for_status()?.text().await?;
+        if is_login_page(&response) {
+            return Err(Error::SessionExpired);
+        }
+        let matches = self
+            .token_exp
+            .captures(response.as_str())
+            .ok_or(Error::TokenNotFound)?;
+        Ok(matches["token"].to_string())
+    }
+}
+
+#[async_trait]
+impl RouterBackend for Luci {
+    async fn login(&mut self) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{}/cgi-bin/luci", self.host))
+            .form(&self.login_field)
+            .send()
+            .await?;
+        // LuCI answers a successful login with a redirect that sets the session cookie
+        if let Some(cookie) = response
+            .cookies()
+            .find(|cookie| cookie.name().starts_with("sysauth"))
+        {
+            self.session = Some(cookie.value().to_string());
+            return Ok(());
+        }
+        let status = response.status();
+        if status == StatusCode::FORBIDDEN || is_login_page(&response.text().await?) {
+            return Err(Error::LoginFailed(
+                "invalid username and/or password".to_string(),
+            ));
+        }
+        Err(Error::LoginFailed(format!(
+            "unexpected response {} without session cookie",
+            status
+        )))
+    }
+
+    async fn fetch_status(&mut self) -> Result<Status> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/cgi-bin/luci/?status=1&_={}",
+                self.host,
+                get_current_timestamp()
+            ))
+            .send()
+            .await
+            .map_err(Error::StatusUnreachable)?;
+        if response.status() == StatusCode::FORBIDDEN {
+            return Err(Error::SessionExpired);
+        }
+        let response = response
+            .error_for_status()
+            .map_err(Error::StatusUnreachable)?
+            .text()
+            .await
+            .map_err(Error::StatusUnreachable)?;
+        if is_login_page(&response) {
+            return Err(Error::SessionExpired);
+        }
+        let response: Map<String, serde_json::Value> =
+            serde_json::from_str(&response).map_err(|e| Error::UnexpectedStatus(e.to_string()))?;
+        parse_status(&response)
+    }
+
+    async fn reboot(&mut self, dry_run: bool) -> Result<()> {
+        let token = self.token("admin/system/reboot").await?;
+        if dry_run {
+            return Ok(());
+        }
+        let response = self
+            .client
+            .post(format!(
+                "{}/cgi-bin/luci/admin/system/reboot/call",
+                self.host
+            ))
+            .form(&TokenField::new(token))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::RebootRejected(format!(
+                "reboot call returned {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn diagnose(&mut self, diagnostic: Diagnostic, target: &str) -> Result<String> {
+        let token = self.token("admin/network/diagnostics").await?;
+        let mut url = reqwest::Url::parse(&format!(
+            "{}/cgi-bin/luci/admin/network/{}",
+            self.host,
+            diagnostic.page()
+        ))
+        .map_err(|e| Error::DiagnosticFailed(e.to_string()))?;
+        // Target is the last path segment, percent-encoded
+        url.path_segments_mut()
+            .map_err(|_| Error::DiagnosticFailed(format!("invalid host {}", self.host)))?
+            .push(target);
+        let response = self
+            .client
+            .post(url)
+            .form(&TokenField::new(token))
+            .send()
+            .await?;
+        if response.status() == StatusCode::FORBIDDEN {
+            return Err(Error::SessionExpired);
+        }
+        let response = response.error_for_status()?.text().await?;
+        if is_login_page(&response) {
+            return Err(Error::SessionExpired);
+        }
+        Ok(response)
+    }
+
+    async fn remediate(&mut self, action: &Action, dry_run: bool) -> Result<()> {
+        let session = self.session.as_deref().ok_or(Error::SessionExpired)?;
+        let url = format!("{}/ubus", self.host);
+        super::ubus::remediate(&self.client, &url, session, action, dry_run).await
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::{Backend, Server};
+use crate::error::Result;
+use crate::probe::Diagnostic;
+use crate::remediation::Action;
+use crate::status::Status;
+use async_trait::async_trait;
+
+mod local;
+mod luci;
+mod rpc;
+mod ssh;
+mod ubus;
+
+pub use local::Local;
+pub use luci::Luci;
+pub use rpc::Rpc;
+pub use ssh::Ssh;
+pub use ubus::Ubus;
+
+/// A way to talk to a router
+#[async_trait]
+pub trait RouterBackend: Send {
+    async fn login(&mut self) -> Result<()>;
+
+    async fn fetch_status(&mut self) -> Result<Status>;
+
+    /// Reboot the router. With `dry_run`, do every preparation step but skip the final call.
+    async fn reboot(&mut self, dry_run: bool) -> Result<()>;
+
+    /// Run a remediation step other than reboot. With `dry_run`, only check it is allowed.
+    async fn remediate(&mut self, action: &Action, dry_run: bool) -> Result<()>;
+
+    /// Run a network diagnostic on the router against `target`, return its output
+    async fn diagnose(&mut self, diagnostic: Diagnostic, target: &str) -> Result<String>;
+}
+
+/// Quote `s` as a single shell word, plain words are kept as is
+fn shell_quote(s: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !s.is_empty() && s.chars().all(plain) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Join `command` and its quoted `args` into a shell command line
+fn shell_command<S: AsRef<str>>(command: &str, args: &[S]) -> String {
+    std::iter::once(command.to_string())
+        .chain(args.iter().map(|arg| shell_quote(arg.as_ref())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Create the backend selected by server configuration
+pub fn from_server(server: &Server) -> anyhow::Result<Box<dyn RouterBackend>> {
+    Ok(match server.backend {
+        Backend::Luci => Box::new(Luci::new(server)?),
+        Backend::Ubus => Box::new(Ubus::new(server)?),
+        Backend::Local => Box::new(Local::new(server)?),
+        Backend::Ssh => Box::new(Ssh::new(server)?),
+        Backend::Rpc => Box::new(Rpc::new(server)?),
+    })
+}
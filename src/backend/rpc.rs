@@ -0,0 +1,200 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::local::CPU_SAMPLE;
+use super::{shell_command, RouterBackend};
+use crate::config::Server;
+use crate::error::{Error, Result};
+use crate::probe::Diagnostic;
+use crate::procfs::{self, CpuTimes};
+use crate::remediation::Action;
+use crate::status::Status;
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct Response {
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+/// Call the luci-mod-rpc JSON-RPC API at `/cgi-bin/luci/rpc`
+pub struct Rpc {
+    client: reqwest::Client,
+    url: String,
+    user: String,
+    password: String,
+    token: Option<String>,
+    cpu: Option<CpuTimes>,
+}
+
+impl Rpc {
+    pub fn new(server: &Server) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(server.timeout))
+                .build()?,
+            url: format!("{}/cgi-bin/luci/rpc", server.get_host()),
+            user: server.user.clone(),
+            password: server.password.clone(),
+            token: None,
+            cpu: None,
+        })
+    }
+
+    /// Call `method` of `library` with the auth token, wrapping RPC failures with `wrap`
+    async fn call(
+        &self,
+        library: &str,
+        method: &str,
+        params: Value,
+        wrap: fn(String) -> Error,
+    ) -> Result<Value> {
+        let token = self.token.as_deref().ok_or(Error::SessionExpired)?;
+        let response = self
+            .client
+            .post(format!("{}/{}", self.url, library))
+            .query(&[("auth", token)])
+            .json(&json!({ "id": 1, "method": method, "params": params }))
+            .send()
+            .await?;
+        if response.status() == StatusCode::FORBIDDEN {
+            return Err(Error::SessionExpired);
+        }
+        let response: Response = response.error_for_status()?.json().await?;
+        match response.error {
+            Some(error) if !error.is_null() => {
+                Err(wrap(format!("{}.{} failed: {}", library, method, error)))
+            }
+            _ => Ok(response.result.unwrap_or(Value::Null)),
+        }
+    }
+
+    /// Run `command` through `sys.exec`, return its stdout
+    async fn exec(&self, command: &str, wrap: fn(String) -> Error) -> Result<String> {
+        let result = self.call("sys", "exec", json!([command]), wrap).await?;
+        Ok(result.as_str().unwrap_or_default().to_string())
+    }
+
+    /// Run `command`, wrap its failure with `wrap`.
+    /// `sys.exec` does not report the exit status, so it is printed after the output.
+    async fn run(&self, command: &str, wrap: fn(String) -> Error) -> Result<String> {
+        let output = self
+            .exec(&format!("{} 2>&1; echo \"@@$?\"", command), wrap)
+            .await?;
+        let (output, code) = output
+            .rsplit_once("@@")
+            .ok_or_else(|| wrap(format!("{} printed no exit status", command)))?;
+        match code.trim() {
+            "0" => Ok(output.to_string()),
+            code => Err(wrap(format!(
+                "{} exited with {}: {}",
+                command,
+                code,
+                output.trim()
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl RouterBackend for Rpc {
+    async fn login(&mut self) -> Result<()> {
+        self.token = None;
+        // Counters reset when the router reboots
+        self.cpu = None;
+        let response = self
+            .client
+            .post(format!("{}/auth", self.url))
+            .json(&json!({ "id": 1, "method": "login", "params": [self.user, self.password] }))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::LoginFailed(
+                "rpc/auth not found, is luci-mod-rpc installed?".to_string(),
+            ));
+        }
+        let response: Response = response.error_for_status()?.json().await?;
+        match response.result {
+            Some(Value::String(token)) => {
+                self.token = Some(token);
+                Ok(())
+            }
+            _ => Err(Error::LoginFailed(
+                "invalid username or password".to_string(),
+            )),
+        }
+    }
+
+    async fn fetch_status(&mut self) -> Result<Status> {
+        if self.cpu.is_none() {
+            let stat = self.exec("cat /proc/stat", Error::UnexpectedStatus).await?;
+            self.cpu = Some(procfs::parse_stat(&stat)?);
+            tokio::time::sleep(CPU_SAMPLE).await;
+        }
+        let output = self
+            .exec(procfs::SNAPSHOT_COMMAND, Error::UnexpectedStatus)
+            .await?;
+        let (now, status) = procfs::parse_snapshot(&output, self.cpu)?;
+        self.cpu = Some(now);
+        Ok(status)
+    }
+
+    async fn reboot(&mut self, dry_run: bool) -> Result<()> {
+        if dry_run {
+            self.run("command -v reboot", Error::RebootRejected).await?;
+            return Ok(());
+        }
+        // Result is the exit status of `reboot`
+        match self
+            .call("sys", "reboot", json!([]), Error::RebootRejected)
+            .await?
+        {
+            Value::Number(code) if code.as_i64() != Some(0) => Err(Error::RebootRejected(format!(
+                "reboot exited with {}",
+                code
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn remediate(&mut self, action: &Action, dry_run: bool) -> Result<()> {
+        let (object, method, args) = action.ubus_call();
+        let command = if dry_run {
+            shell_command("ubus", &["list", object])
+        } else {
+            shell_command("ubus", &["call", object, method, &args.to_string()])
+        };
+        self.run(&command, Error::RemediationFailed).await?;
+        Ok(())
+    }
+
+    async fn diagnose(&mut self, diagnostic: Diagnostic, target: &str) -> Result<String> {
+        let (command, args) = diagnostic.command(target);
+        self.exec(
+            &format!("{} 2>&1", shell_command(command, &args)),
+            Error::DiagnosticFailed,
+        )
+        .await
+    }
+}
@@ -0,0 +1,287 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::local::CPU_SAMPLE;
+use super::{shell_command, RouterBackend};
+use crate::config::Server;
+use crate::error::{Error, Result};
+use crate::probe::Diagnostic;
+use crate::procfs::{self, CpuTimes};
+use crate::remediation::Action;
+use crate::status::Status;
+use async_trait::async_trait;
+use russh::client::{self, Handle};
+use russh::ChannelMsg;
+use russh_keys::key::PublicKey;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Seconds between keepalive messages, the connection is closed after three unanswered ones
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Accept only server keys recorded in a known_hosts file
+struct KnownHosts {
+    host: String,
+    port: u16,
+    path: Option<String>,
+}
+
+#[async_trait]
+impl client::Handler for KnownHosts {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        key: &PublicKey,
+    ) -> std::result::Result<bool, Self::Error> {
+        let known = match &self.path {
+            Some(path) => russh_keys::check_known_hosts_path(&self.host, self.port, key, path),
+            None => russh_keys::check_known_hosts(&self.host, self.port, key),
+        };
+        match known {
+            Err(russh_keys::Error::KeyChanged { line }) => Err(russh::Error::KeyChanged { line }),
+            ret => Ok(ret?),
+        }
+    }
+}
+
+struct Output {
+    code: Option<u32>,
+    stdout: String,
+    stderr: String,
+}
+
+/// Run commands on the router over SSH
+pub struct Ssh {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    key: Option<String>,
+    key_passphrase: Option<String>,
+    known_hosts: Option<String>,
+    timeout: Duration,
+    handle: Option<Handle<KnownHosts>>,
+    cpu: Option<CpuTimes>,
+}
+
+impl Ssh {
+    pub fn new(server: &Server) -> anyhow::Result<Self> {
+        let user = match server.user.as_str() {
+            "" => "root",
+            user => user,
+        };
+        Ok(Self {
+            host: server.host.clone(),
+            port: server.ssh_port,
+            user: user.to_string(),
+            password: server.password.clone(),
+            key: server.ssh_key.clone(),
+            key_passphrase: server.ssh_key_passphrase.clone(),
+            known_hosts: server.known_hosts.clone(),
+            timeout: Duration::from_secs(server.timeout),
+            handle: None,
+            cpu: None,
+        })
+    }
+
+    fn known_hosts_path(&self) -> &str {
+        self.known_hosts.as_deref().unwrap_or("~/.ssh/known_hosts")
+    }
+
+    async fn connect(&self) -> Result<Handle<KnownHosts>> {
+        let handler = KnownHosts {
+            host: self.host.clone(),
+            port: self.port,
+            path: self.known_hosts.clone(),
+        };
+        let connect = client::connect(
+            Arc::new(client::Config {
+                keepalive_interval: Some(KEEPALIVE),
+                keepalive_max: 3,
+                ..Default::default()
+            }),
+            (self.host.as_str(), self.port),
+            handler,
+        );
+        let address = format!("{}:{}", self.host, self.port);
+        match tokio::time::timeout(self.timeout, connect).await {
+            Ok(Ok(handle)) => Ok(handle),
+            Ok(Err(russh::Error::UnknownKey)) => Err(Error::LoginFailed(format!(
+                "host key of {} not found in {}",
+                address,
+                self.known_hosts_path()
+            ))),
+            Ok(Err(russh::Error::KeyChanged { line })) => Err(Error::LoginFailed(format!(
+                "host key of {} does not match line {} of {}",
+                address,
+                line,
+                self.known_hosts_path()
+            ))),
+            Ok(Err(russh::Error::Keys(e))) => Err(Error::LoginFailed(format!(
+                "unable to check host key with {}: {}",
+                self.known_hosts_path(),
+                e
+            ))),
+            Ok(Err(e)) => Err(Error::Connection(format!("{}: {}", address, e))),
+            Err(_) => Err(Error::Connection(format!("{}: timed out", address))),
+        }
+    }
+
+    async fn authenticate(&self, handle: &mut Handle<KnownHosts>) -> Result<()> {
+        let accepted = match &self.key {
+            Some(path) => {
+                let key = russh_keys::load_secret_key(path, self.key_passphrase.as_deref())
+                    .map_err(|e| {
+                        Error::LoginFailed(format!("unable to load key {}: {}", path, e))
+                    })?;
+                handle
+                    .authenticate_publickey(&self.user, Arc::new(key))
+                    .await
+            }
+            None => {
+                handle
+                    .authenticate_password(&self.user, &self.password)
+                    .await
+            }
+        }
+        .map_err(|e| Error::Connection(e.to_string()))?;
+        if !accepted {
+            return Err(Error::LoginFailed(format!(
+                "authentication rejected for {}",
+                self.user
+            )));
+        }
+        Ok(())
+    }
+
+    /// Run `command` on the router, a lost connection is reported as expired session
+    async fn exec(&self, command: &str) -> Result<Output> {
+        tokio::time::timeout(self.timeout, self.exec_unbounded(command))
+            .await
+            .unwrap_or(Err(Error::Timeout(self.timeout.as_secs())))
+    }
+
+    async fn exec_unbounded(&self, command: &str) -> Result<Output> {
+        let handle = self.handle.as_ref().ok_or(Error::SessionExpired)?;
+        let mut channel = handle.channel_open_session().await.map_err(|_| {
+            if handle.is_closed() {
+                Error::Connection("connection closed".to_string())
+            } else {
+                Error::SessionExpired
+            }
+        })?;
+        channel
+            .exec(true, command)
+            .await
+            .map_err(|_| Error::SessionExpired)?;
+        let mut output = Output {
+            code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => output.stdout += &String::from_utf8_lossy(&data),
+                ChannelMsg::ExtendedData { data, .. } => {
+                    output.stderr += &String::from_utf8_lossy(&data)
+                }
+                ChannelMsg::ExitStatus { exit_status } => output.code = Some(exit_status),
+                _ => {}
+            }
+        }
+        Ok(output)
+    }
+
+    /// Run `command`, wrap its failure with `wrap`
+    async fn run(&self, command: &str, wrap: fn(String) -> Error) -> Result<String> {
+        let output = self.exec(command).await?;
+        match output.code {
+            Some(0) => Ok(output.stdout),
+            code => Err(wrap(format!(
+                "{} exited with {}: {}",
+                command,
+                code.map_or("no status".to_string(), |code| code.to_string()),
+                output.stderr.trim()
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl RouterBackend for Ssh {
+    async fn login(&mut self) -> Result<()> {
+        self.handle = None;
+        // Counters reset when the router reboots
+        self.cpu = None;
+        let mut handle = self.connect().await?;
+        self.authenticate(&mut handle).await?;
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    async fn fetch_status(&mut self) -> Result<Status> {
+        if self.cpu.is_none() {
+            let stat = self.run("cat /proc/stat", Error::UnexpectedStatus).await?;
+            self.cpu = Some(procfs::parse_stat(&stat)?);
+            tokio::time::sleep(CPU_SAMPLE).await;
+        }
+        let output = self.exec(procfs::SNAPSHOT_COMMAND).await?;
+        let (now, status) = procfs::parse_snapshot(&output.stdout, self.cpu)
+            .map_err(|e| Error::UnexpectedStatus(format!("{}: {}", e, output.stderr.trim())))?;
+        self.cpu = Some(now);
+        Ok(status)
+    }
+
+    async fn reboot(&mut self, dry_run: bool) -> Result<()> {
+        if dry_run {
+            self.run("command -v reboot", Error::RebootRejected).await?;
+            return Ok(());
+        }
+        let output = self.exec("reboot").await?;
+        // Connection may drop before the exit status arrives
+        if let Some(code @ 1..) = output.code {
+            return Err(Error::RebootRejected(format!(
+                "reboot exited with {}: {}",
+                code,
+                output.stderr.trim()
+            )));
+        }
+        // Keep the connection, verification sees the router go down when it drops
+        Ok(())
+    }
+
+    async fn remediate(&mut self, action: &Action, dry_run: bool) -> Result<()> {
+        let (object, method, args) = action.ubus_call();
+        let command = if dry_run {
+            shell_command("ubus", &["list", object])
+        } else {
+            shell_command("ubus", &["call", object, method, &args.to_string()])
+        };
+        self.run(&command, Error::RemediationFailed).await?;
+        Ok(())
+    }
+
+    async fn diagnose(&mut self, diagnostic: Diagnostic, target: &str) -> Result<String> {
+        let (command, args) = diagnostic.command(target);
+        let output = self.exec(&shell_command(command, &args)).await?;
+        Ok(output.stdout + &output.stderr)
+    }
+}
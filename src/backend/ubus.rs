@@ -0,0 +1,344 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::RouterBackend;
+use crate::config::Server;
+use crate::error::{Error, Result};
+use crate::probe::Diagnostic;
+use crate::remediation::Action;
+use crate::status::{Memory, Status, LOAD_SCALE};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Session id used before login
+const NULL_SESSION: &str = "00000000000000000000000000000000";
+
+/// JSON-RPC error returned by uhttpd when the session is invalid or expired
+const ACCESS_DENIED: i64 = -32002;
+
+#[derive(Deserialize)]
+struct ErrorObject {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: Option<Vec<Value>>,
+    error: Option<ErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct LoginResult {
+    ubus_rpc_session: String,
+}
+
+#[derive(Deserialize)]
+struct AccessResult {
+    access: bool,
+}
+
+#[derive(Deserialize)]
+struct ExecResult {
+    /// Exit status of the command
+    #[serde(default)]
+    code: i32,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+}
+
+#[derive(Deserialize)]
+struct SystemInfo {
+    uptime: u64,
+    load: Vec<u64>,
+    memory: Memory,
+}
+
+impl From<SystemInfo> for Status {
+    fn from(info: SystemInfo) -> Self {
+        Self {
+            cpu_usage: None,
+            load_avg: info
+                .load
+                .into_iter()
+                .map(|x| x as f64 / LOAD_SCALE)
+                .collect(),
+            memory: Some(info.memory),
+            uptime: Some(info.uptime),
+            conntrack: None,
+            extra: Default::default(),
+        }
+    }
+}
+
+/// Failure of a single JSON-RPC call
+pub(super) enum CallError {
+    Request(reqwest::Error),
+    AccessDenied,
+    Rpc(String),
+}
+
+impl CallError {
+    /// Convert to crate error, wrapping RPC failures with `wrap`
+    fn into_error(self, wrap: fn(String) -> Error) -> Error {
+        match self {
+            CallError::Request(e) => Error::Request(e),
+            CallError::AccessDenied => wrap("access denied".to_string()),
+            CallError::Rpc(message) => wrap(message),
+        }
+    }
+}
+
+impl From<reqwest::Error> for CallError {
+    fn from(e: reqwest::Error) -> Self {
+        CallError::Request(e)
+    }
+}
+
+/// Send one JSON-RPC call to `url` within `session`
+pub(super) async fn call(
+    client: &reqwest::Client,
+    url: &str,
+    session: &str,
+    object: &str,
+    method: &str,
+    args: Value,
+) -> std::result::Result<Option<Value>, CallError> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "call",
+        "params": [session, object, method, args],
+    });
+    let response: Response = client.post(url).json(&body).send().await?.json().await?;
+    if let Some(error) = response.error {
+        if error.code == ACCESS_DENIED {
+            return Err(CallError::AccessDenied);
+        }
+        return Err(CallError::Rpc(format!(
+            "ubus call {}.{} failed: {} ({})",
+            object, method, error.message, error.code
+        )));
+    }
+    let mut result = response
+        .result
+        .ok_or_else(|| {
+            CallError::Rpc(format!(
+                "ubus call {}.{} returned no result",
+                object, method
+            ))
+        })?
+        .into_iter();
+    match result.next().and_then(|x| x.as_i64()) {
+        Some(0) => Ok(result.next()),
+        Some(code) => Err(CallError::Rpc(format!(
+            "ubus call {}.{} returned status {}",
+            object, method, code
+        ))),
+        None => Err(CallError::Rpc(format!(
+            "ubus call {}.{} returned malformed result",
+            object, method
+        ))),
+    }
+}
+
+/// Check that `session` may call `object.method`
+async fn check_access(
+    client: &reqwest::Client,
+    url: &str,
+    session: &str,
+    object: &str,
+    method: &str,
+    wrap: fn(String) -> Error,
+) -> Result<()> {
+    let result = call(
+        client,
+        url,
+        session,
+        "session",
+        "access",
+        json!({ "scope": "ubus", "object": object, "function": method }),
+    )
+    .await
+    .map_err(|e| e.into_error(wrap))?
+    .ok_or_else(|| wrap("session.access returned no data".to_string()))?;
+    let result: AccessResult = serde_json::from_value(result).map_err(|e| wrap(e.to_string()))?;
+    if !result.access {
+        return Err(wrap(format!(
+            "session has no access to {}.{}",
+            object, method
+        )));
+    }
+    Ok(())
+}
+
+/// Run remediation `action` other than reboot within `session`.
+/// With `dry_run`, only check the session may run it.
+pub(super) async fn remediate(
+    client: &reqwest::Client,
+    url: &str,
+    session: &str,
+    action: &Action,
+    dry_run: bool,
+) -> Result<()> {
+    let (object, method, args) = action.ubus_call();
+    if dry_run {
+        return check_access(
+            client,
+            url,
+            session,
+            object,
+            method,
+            Error::RemediationFailed,
+        )
+        .await;
+    }
+    let result = call(client, url, session, object, method, args)
+        .await
+        .map_err(|e| e.into_error(Error::RemediationFailed))?;
+    // file.exec succeeds whatever the exit status of the command is
+    if let Some(result) = result.filter(|_| (object, method) == ("file", "exec")) {
+        let result: ExecResult =
+            serde_json::from_value(result).map_err(|e| Error::RemediationFailed(e.to_string()))?;
+        if result.code != 0 {
+            return Err(Error::RemediationFailed(format!(
+                "{} exited with {}: {}",
+                action,
+                result.code,
+                result.stderr.trim()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Client of the rpcd JSON-RPC endpoint at `/ubus`
+pub struct Ubus {
+    client: reqwest::Client,
+    url: String,
+    user: String,
+    password: String,
+    session: Option<String>,
+}
+
+impl Ubus {
+    pub fn new(server: &Server) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(server.timeout))
+                .build()?,
+            url: format!("{}/ubus", server.get_host()),
+            user: server.user.clone(),
+            password: server.password.clone(),
+            session: None,
+        })
+    }
+
+    async fn call(
+        &self,
+        object: &str,
+        method: &str,
+        args: Value,
+    ) -> std::result::Result<Option<Value>, CallError> {
+        let session = self.session.as_deref().unwrap_or(NULL_SESSION);
+        call(&self.client, &self.url, session, object, method, args).await
+    }
+
+    async fn system_info(&self) -> Result<SystemInfo> {
+        let result = self
+            .call("system", "info", json!({}))
+            .await
+            .map_err(|e| match e {
+                CallError::Request(e) => Error::StatusUnreachable(e),
+                CallError::AccessDenied => Error::SessionExpired,
+                CallError::Rpc(message) => Error::UnexpectedStatus(message),
+            })?
+            .ok_or_else(|| Error::UnexpectedStatus("system.info returned no data".to_string()))?;
+        serde_json::from_value(result).map_err(|e| Error::UnexpectedStatus(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl RouterBackend for Ubus {
+    async fn login(&mut self) -> Result<()> {
+        self.session = None;
+        let result = self
+            .call(
+                "session",
+                "login",
+                json!({ "username": self.user, "password": self.password }),
+            )
+            .await
+            .map_err(|e| e.into_error(Error::LoginFailed))?
+            .ok_or_else(|| Error::LoginFailed("ubus login returned no session".to_string()))?;
+        let result: LoginResult =
+            serde_json::from_value(result).map_err(|e| Error::LoginFailed(e.to_string()))?;
+        self.session = Some(result.ubus_rpc_session);
+        Ok(())
+    }
+
+    async fn fetch_status(&mut self) -> Result<Status> {
+        Ok(self.system_info().await?.into())
+    }
+
+    async fn reboot(&mut self, dry_run: bool) -> Result<()> {
+        if dry_run {
+            let session = self.session.as_deref().unwrap_or(NULL_SESSION);
+            return check_access(
+                &self.client,
+                &self.url,
+                session,
+                "system",
+                "reboot",
+                Error::RebootRejected,
+            )
+            .await;
+        }
+        self.call("system", "reboot", json!({}))
+            .await
+            .map_err(|e| e.into_error(Error::RebootRejected))?;
+        Ok(())
+    }
+
+    async fn diagnose(&mut self, diagnostic: Diagnostic, target: &str) -> Result<String> {
+        let (command, params) = diagnostic.command(target);
+        let result = self
+            .call(
+                "file",
+                "exec",
+                json!({ "command": command, "params": params }),
+            )
+            .await
+            .map_err(|e| e.into_error(Error::DiagnosticFailed))?
+            .ok_or_else(|| Error::DiagnosticFailed("file.exec returned no data".to_string()))?;
+        let result: ExecResult =
+            serde_json::from_value(result).map_err(|e| Error::DiagnosticFailed(e.to_string()))?;
+        Ok(result.stdout + &result.stderr)
+    }
+
+    async fn remediate(&mut self, action: &Action, dry_run: bool) -> Result<()> {
+        let session = self.session.as_deref().unwrap_or(NULL_SESSION);
+        remediate(&self.client, &self.url, session, action, dry_run).await
+    }
+}
@@ -0,0 +1,390 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::maintenance::MaintenanceWindow;
+use crate::notify::telegram::TelegramConfig;
+use crate::notify::NotifyConfig;
+use crate::policy::{Threshold, Window};
+use crate::probe::Probe;
+use crate::remediation::Remediation;
+use crate::rule::Rule;
+use crate::schedule::RebootSchedule;
+use anyhow::bail;
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Scrape LuCI web interface
+    #[default]
+    Luci,
+    /// Call rpcd through `/ubus` JSON-RPC endpoint
+    Ubus,
+    /// Read procfs of the machine the monitor runs on
+    Local,
+    /// Run commands over SSH
+    Ssh,
+    /// Call luci-mod-rpc JSON-RPC API at `/cgi-bin/luci/rpc`
+    Rpc,
+}
+
+/// How the local backend reboots
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalReboot {
+    /// Run `ubus call system reboot`
+    #[default]
+    Ubus,
+    /// Call the reboot syscall, needs root
+    Syscall,
+}
+
+fn default_proc_root() -> String {
+    "/proc".to_string()
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_login_attempts() -> u32 {
+    3
+}
+
+fn default_login_backoff() -> u64 {
+    2
+}
+
+/// Login retry when the router can not be reached
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Retry {
+    #[serde(default = "default_login_attempts")]
+    pub attempts: u32,
+    /// Seconds before the first retry, doubled after every attempt
+    #[serde(default = "default_login_backoff")]
+    pub backoff: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: default_login_attempts(),
+            backoff: default_login_backoff(),
+        }
+    }
+}
+
+fn default_verify_interval() -> u64 {
+    10
+}
+
+fn default_down_timeout() -> u64 {
+    120
+}
+
+fn default_up_timeout() -> u64 {
+    300
+}
+
+/// Wait for the router to come back after reboot
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Verify {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds between two polls
+    #[serde(default = "default_verify_interval")]
+    pub interval: u64,
+    /// Seconds to wait for the router to become unreachable
+    #[serde(default = "default_down_timeout")]
+    pub down_timeout: u64,
+    /// Seconds to wait for the router to answer again
+    #[serde(default = "default_up_timeout")]
+    pub up_timeout: u64,
+}
+
+impl Default for Verify {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: default_verify_interval(),
+            down_timeout: default_down_timeout(),
+            up_timeout: default_up_timeout(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_cooldown() -> u64 {
+    1800
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Server {
+    pub name: Option<String>,
+    /// Required by every backend but `local`, which defaults to `localhost`
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub backend: Backend,
+    /// Seconds before a request to the router is abandoned
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// procfs mount point read by the local backend
+    #[serde(default = "default_proc_root")]
+    pub proc_root: String,
+    /// Reboot method of the local backend
+    #[serde(default)]
+    pub local_reboot: LocalReboot,
+    /// Port of the ssh backend
+    #[serde(default = "default_ssh_port")]
+    pub ssh_port: u16,
+    /// Private key of the ssh backend, `password` is used when unset
+    pub ssh_key: Option<String>,
+    pub ssh_key_passphrase: Option<String>,
+    /// Host keys accepted by the ssh backend, `~/.ssh/known_hosts` when unset
+    pub known_hosts: Option<String>,
+    #[serde(default)]
+    pub threshold: Threshold,
+    /// Once unhealthy, router stays unhealthy until it falls below these thresholds
+    pub recovery: Option<Threshold>,
+    /// Health rule over named metrics, replaces `threshold` and `recovery` when set
+    pub rule: Option<Rule>,
+    #[serde(default)]
+    pub window: Window,
+    /// Evaluate everything but never send the reboot call
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub login_retry: Retry,
+    /// Minimum seconds between two reboots
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    /// Maximum reboots in any 24 hours, 0 for unlimited
+    #[serde(default)]
+    pub max_reboots_per_day: usize,
+    #[serde(default)]
+    pub verify: Verify,
+    #[serde(default)]
+    pub remediation: Remediation,
+    /// Reboot only within these windows, replaces the global windows when set
+    pub maintenance: Option<Vec<MaintenanceWindow>>,
+    /// Rule that allows reboot outside maintenance windows
+    pub emergency: Option<Rule>,
+    /// Connectivity checks run from the monitoring host on every poll
+    #[serde(default)]
+    pub probes: Vec<Probe>,
+    /// Reboot unconditionally on this schedule
+    pub schedule: Option<RebootSchedule>,
+    /// Reboot when uptime exceeds this many days, 0 to disable
+    #[serde(default)]
+    pub max_uptime_days: u64,
+    /// Telegram settings of this router, merged over `notify.telegram`
+    pub telegram: Option<TelegramConfig>,
+}
+
+impl Server {
+    pub fn new(host: String, user: String, password: String) -> Self {
+        Self {
+            name: None,
+            host,
+            user,
+            password,
+            backend: Default::default(),
+            timeout: default_timeout(),
+            proc_root: default_proc_root(),
+            local_reboot: Default::default(),
+            ssh_port: default_ssh_port(),
+            ssh_key: None,
+            ssh_key_passphrase: None,
+            known_hosts: None,
+            threshold: Default::default(),
+            recovery: None,
+            rule: None,
+            window: Default::default(),
+            dry_run: false,
+            login_retry: Default::default(),
+            cooldown: default_cooldown(),
+            max_reboots_per_day: 0,
+            verify: Default::default(),
+            remediation: Default::default(),
+            maintenance: None,
+            emergency: None,
+            probes: Vec::new(),
+            schedule: None,
+            max_uptime_days: 0,
+            telegram: None,
+        }
+    }
+
+    pub fn get_host(&self) -> &String {
+        &self.host
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.host)
+    }
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_interval() -> u64 {
+    300
+}
+
+fn default_jitter() -> u64 {
+    30
+}
+
+fn default_check_timeout() -> u64 {
+    900
+}
+
+fn default_state_file() -> String {
+    "state.json".to_string()
+}
+
+/// Accept a single `[server]` table as well as `[[server]]` entries
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Server>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = toml::Value::deserialize(deserializer)?;
+    let servers = if value.is_table() {
+        Server::deserialize(value).map(|server| vec![server])
+    } else {
+        Vec::<Server>::deserialize(value)
+    };
+    servers.map_err(serde::de::Error::custom)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Seconds between two polls in daemon mode
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Maximum random seconds added to every interval in daemon mode
+    #[serde(default = "default_jitter")]
+    pub jitter: u64,
+    /// Seconds before the check of one router is abandoned, including remediation and verification
+    #[serde(default = "default_check_timeout")]
+    pub check_timeout: u64,
+    /// File that keeps reboot history and health samples between runs
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    /// Address of the Prometheus `/metrics` listener in daemon mode, disabled if unset
+    pub metrics_listen: Option<SocketAddr>,
+    /// Default maintenance windows of every server
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindow>,
+    /// Routers to check, a single `[server]` table is accepted too
+    #[serde(deserialize_with = "one_or_many")]
+    pub server: Vec<Server>,
+}
+
+impl Config {
+    pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let context = tokio::fs::read_to_string(path).await?;
+        let mut config: Self = toml::from_str(context.as_str())?;
+        if let Some(global) = &config.notify.telegram {
+            for server in &mut config.server {
+                server.telegram = Some(match &server.telegram {
+                    Some(telegram) => telegram.merge(global),
+                    None => global.clone(),
+                });
+            }
+        }
+        for server in &mut config.server {
+            if server.host.is_empty() {
+                if server.backend != Backend::Local {
+                    bail!(
+                        "server {}: host is required",
+                        server.name.as_deref().unwrap_or_default()
+                    );
+                }
+                server.host = "localhost".to_string();
+            }
+        }
+        let mut names = HashSet::new();
+        for server in &config.server {
+            if !names.insert(server.get_name()) {
+                bail!(
+                    "server {}: name is used by another server, set a unique name",
+                    server.get_name()
+                );
+            }
+            for (key, rule) in [("rule", &server.rule), ("emergency", &server.emergency)] {
+                if let Some(Err(e)) = rule.as_ref().map(Rule::validate) {
+                    bail!("server {}: invalid {}: {}", server.get_name(), key, e);
+                }
+            }
+        }
+        for server in &mut config.server {
+            if server.maintenance.is_none() {
+                server.maintenance = Some(config.maintenance.clone());
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn from_server(server: Server) -> Self {
+        Self {
+            concurrency: default_concurrency(),
+            interval: default_interval(),
+            jitter: default_jitter(),
+            check_timeout: default_check_timeout(),
+            state_file: default_state_file(),
+            notify: Default::default(),
+            metrics_listen: None,
+            maintenance: Vec::new(),
+            server: vec![server],
+        }
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        let jitter = if self.jitter > 0 {
+            rand::thread_rng().gen_range(0..=self.jitter * 1000)
+        } else {
+            0
+        };
+        Duration::from_secs(self.interval) + Duration::from_millis(jitter)
+    }
+}
@@ -0,0 +1,68 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors of talking to a router
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("login failed: {0}")]
+    LoginFailed(String),
+    #[error("session expired")]
+    SessionExpired,
+    #[error("status unreachable: {0}")]
+    StatusUnreachable(#[source] reqwest::Error),
+    #[error("unexpected status schema: {0}")]
+    UnexpectedStatus(String),
+    #[error("reboot token not found in reboot page")]
+    TokenNotFound,
+    #[error("reboot rejected: {0}")]
+    RebootRejected(String),
+    #[error("remediation failed: {0}")]
+    RemediationFailed(String),
+    #[error("diagnostic failed: {0}")]
+    DiagnosticFailed(String),
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("connection failed: {0}")]
+    Connection(String),
+    #[error("timed out after {0}s")]
+    Timeout(u64),
+}
+
+impl Error {
+    /// Process exit code reported when a router fails with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::LoginFailed(_) | Error::SessionExpired => 2,
+            Error::StatusUnreachable(_)
+            | Error::Request(_)
+            | Error::Connection(_)
+            | Error::Timeout(_) => 3,
+            Error::UnexpectedStatus(_) => 4,
+            Error::TokenNotFound => 5,
+            Error::RebootRejected(_) => 6,
+            // Never leave a check: failed remediation escalates, failed diagnostic fails its probe
+            Error::RemediationFailed(_) | Error::DiagnosticFailed(_) => 1,
+        }
+    }
+}
@@ -19,167 +19,155 @@
  */
 
 use clap::{App, Arg, ArgMatches};
-use log::{info, warn};
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use serde_json::Map;
+use log::error;
+use openwrt_autoreboot::config::{Config, Server};
+use openwrt_autoreboot::error::Error;
+use openwrt_autoreboot::metrics;
+use openwrt_autoreboot::monitor::{self, Context, Monitor};
+use openwrt_autoreboot::notify::Dispatcher;
+use openwrt_autoreboot::policy::Threshold;
+use openwrt_autoreboot::state::StateStore;
+use std::time::Duration;
 
-pub fn get_current_timestamp() -> u64 {
-    let start = std::time::SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Time went backwards");
-    since_the_epoch.as_secs()
+fn server_from_matches(matches: &ArgMatches) -> Option<Server> {
+    matches.value_of("password")?;
+    Some(Server::new(
+        matches.value_of("host").unwrap().to_string(),
+        matches.value_of("user").unwrap().to_string(),
+        matches.value_of("password").unwrap().to_string(),
+    ))
 }
 
-#[derive(Deserialize, Serialize)]
-struct TokenField {
-    token: String,
-}
-
-impl TokenField {
-    fn new(token: String) -> Self {
-        Self { token }
+fn override_threshold(threshold: &mut Threshold, matches: &ArgMatches) {
+    if matches.is_present("cpu") {
+        threshold.cpu = matches.value_of_t_or_exit("cpu");
     }
-}
-
-#[derive(Deserialize, Serialize)]
-struct LuciLoginField {
-    luci_username: String,
-    luci_password: String,
-}
-
-impl From<&Server> for LuciLoginField {
-    fn from(server: &Server) -> Self {
-        Self {
-            luci_password: server.password.clone(),
-            luci_username: server.user.clone(),
-        }
+    if matches.is_present("load1") {
+        threshold.load1 = matches.value_of_t_or_exit("load1");
     }
-}
-
-#[derive(Deserialize, Serialize)]
-struct Server {
-    host: String,
-    user: String,
-    password: String,
-}
-
-impl Server {
-    fn try_from_matches(matches: &ArgMatches) -> Option<Self> {
-        matches.value_of("password")?;
-        Some(Self {
-            host: matches.value_of("host").unwrap().to_string(),
-            user: matches.value_of("user").unwrap().to_string(),
-            password: matches.value_of("password").unwrap().to_string(),
-        })
+    if matches.is_present("load5") {
+        threshold.load5 = matches.value_of_t_or_exit("load5");
     }
-
-    fn get_host(&self) -> &String {
-        &self.host
+    if matches.is_present("load15") {
+        threshold.load15 = matches.value_of_t_or_exit("load15");
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct Config {
-    server: Server,
-}
-
-impl Config {
-    pub async fn load() -> anyhow::Result<Self> {
-        let context = tokio::fs::read_to_string("config.toml").await?;
-        Ok(toml::from_str(context.as_str())?)
+async fn wait_for_shutdown() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(()),
+            ret = tokio::signal::ctrl_c() => ret,
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
 }
 
 async fn async_main(matches: &ArgMatches) -> anyhow::Result<()> {
-    let server = if let Some(server) = Server::try_from_matches(matches) {
-        server
+    let mut config = if let Some(server) = server_from_matches(matches) {
+        Config::from_server(server)
     } else {
-        let config = Config::load().await?;
-        config.server
+        Config::load("config.toml").await?
     };
-    let token_exp = Regex::new(r"token: '(?P<token>[\da-f]{32})'")?;
-    let client = reqwest::ClientBuilder::new().cookie_store(true).build()?;
-    client
-        .post(format!("{}/cgi-bin/luci", server.get_host()))
-        .form(&LuciLoginField::from(&server))
-        .send()
-        .await?;
-    let response = client
-        .get(format!(
-            "{}/cgi-bin/luci/?status=1&_={}",
-            server.get_host(),
-            get_current_timestamp()
-        ))
-        .send()
-        .await?;
-    let response: Map<String, serde_json::Value> = response.json().await?;
-    if let Some(serde_json::Value::String(cpu)) = response.get("cpuusage") {
-        let (usage, _) = cpu.split_once("\n").unwrap();
-        let cpu_usage = usage.parse::<i32>().unwrap();
-        if cpu_usage > 20 {
-            info!(
-                "Current cpu usage is {}, checking is always in this value",
-                cpu_usage
-            );
-            if let Some(serde_json::Value::Array(load_avg)) = response.get("loadavg") {
-                if load_avg
-                    .iter()
-                    .map(|x| {
-                        if let serde_json::Value::Number(n) = x {
-                            let value = n.as_i64().unwrap();
-                            if value > 65000 {
-                                info!("Current load average value is {}", value);
-                            }
-                            value > 65000
-                        } else {
-                            false
-                        }
-                    })
-                    .all(|x| x)
-                {
-                    warn!("Should call reboot now, performance OpenWRT reboot");
-                    let response = client
-                        .get(format!(
-                            "{}/cgi-bin/luci/admin/system/reboot",
-                            server.get_host()
-                        ))
-                        .send()
-                        .await?;
-                    let response = response.text().await?;
-                    let matches = token_exp.captures(response.as_str()).unwrap();
-                    let token = &matches["token"];
-                    client
-                        .post(format!(
-                            "{}/cgi-bin/luci/admin/system/reboot/call",
-                            server.get_host()
-                        ))
-                        .form(&TokenField::new(token.to_string()))
-                        .send()
-                        .await?;
-                }
-            }
-        } else {
-            info!(
-                "Current cpu usage is {}, there is nothing to do.",
-                cpu_usage
-            )
+    for server in &mut config.server {
+        override_threshold(&mut server.threshold, matches);
+        if matches.is_present("dry-run") {
+            server.dry_run = true;
+        }
+    }
+    let context = Context::new(
+        StateStore::load(&config.state_file).await?,
+        Dispatcher::from_config(&config.notify)?,
+    );
+    let mut monitors = config
+        .server
+        .iter()
+        .map(|server| Monitor::from_server(server, context.clone()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if matches.subcommand_matches("daemon").is_some() {
+        if let Some(address) = config.metrics_listen {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            tokio::spawn(metrics::serve(listener, context.metrics.clone()));
         }
+        return monitor::run_daemon(&config, &mut monitors, wait_for_shutdown()).await;
+    }
+
+    let total = monitors.len();
+    let failures = monitor::check_all(
+        &mut monitors,
+        config.concurrency,
+        Duration::from_secs(config.check_timeout),
+    )
+    .await;
+    let failed = failures.len();
+    if let Some(error) = failures.into_iter().next() {
+        return Err(
+            anyhow::Error::new(error).context(format!("{} of {} routers failed", failed, total))
+        );
     }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
+/// Exit code of the first failed router, 1 for any other error
+fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .downcast_ref::<Error>()
+        .map(Error::exit_code)
+        .unwrap_or(1)
+}
+
+fn main() {
     env_logger::Builder::from_default_env().init();
     let matches = App::new("Auto reboot openwrt service")
         .version(env!("CARGO_PKG_VERSION"))
         .arg(Arg::new("host").about("Specify remote host"))
         .arg(Arg::new("user").about("Specify host username"))
         .arg(Arg::new("password").about("Specify host password"))
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .about("Evaluate every router but never send the reboot call"),
+        )
+        .arg(
+            Arg::new("cpu")
+                .long("cpu")
+                .takes_value(true)
+                .about("Override cpu usage threshold in percent"),
+        )
+        .arg(
+            Arg::new("load1")
+                .long("load1")
+                .takes_value(true)
+                .about("Override 1 minute load average threshold"),
+        )
+        .arg(
+            Arg::new("load5")
+                .long("load5")
+                .takes_value(true)
+                .about("Override 5 minutes load average threshold"),
+        )
+        .arg(
+            Arg::new("load15")
+                .long("load15")
+                .takes_value(true)
+                .about("Override 15 minutes load average threshold"),
+        )
+        .subcommand(
+            App::new("daemon").about("Keep running and poll routers on the configured interval"),
+        )
         .get_matches();
-    tokio::runtime::Builder::new_current_thread()
+    let result = tokio::runtime::Builder::new_current_thread()
         .enable_all()
-        .build()?
-        .block_on(async_main(&matches))
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| runtime.block_on(async_main(&matches)));
+    if let Err(e) = result {
+        error!("{:?}", e);
+        std::process::exit(exit_code(&e));
+    }
 }
@@ -0,0 +1,109 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Time of day written as `HH:MM`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(NaiveTime);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        NaiveTime::parse_from_str(&value, "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|_| format!("time {:?} is not in HH:MM format", value))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.0.format("%H:%M").to_string()
+    }
+}
+
+/// Time range on some days of week in which reboots are allowed
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "RawWindow")]
+pub struct MaintenanceWindow {
+    /// Days the window starts on, e.g. `["sat", "sun"]`, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    /// Range passes midnight when end is before start
+    pub end: TimeOfDay,
+    /// IANA timezone such as `Europe/Berlin`, UTC if unset
+    pub timezone: Option<Tz>,
+}
+
+#[derive(Deserialize)]
+struct RawWindow {
+    #[serde(default)]
+    days: Vec<Weekday>,
+    start: TimeOfDay,
+    end: TimeOfDay,
+    timezone: Option<Tz>,
+}
+
+impl TryFrom<RawWindow> for MaintenanceWindow {
+    type Error = String;
+
+    fn try_from(raw: RawWindow) -> Result<Self, Self::Error> {
+        if raw.start == raw.end {
+            return Err(format!(
+                "window start and end are both {}, it would never be open",
+                String::from(raw.start)
+            ));
+        }
+        Ok(MaintenanceWindow {
+            days: raw.days,
+            start: raw.start,
+            end: raw.end,
+            timezone: raw.timezone,
+        })
+    }
+}
+
+impl MaintenanceWindow {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let now = now.with_timezone(&self.timezone.unwrap_or(Tz::UTC));
+        let time = TimeOfDay(now.time());
+        let day = now.weekday();
+        if self.start <= self.end {
+            self.on(day) && self.start <= time && time < self.end
+        } else {
+            (self.on(day) && time >= self.start) || (self.on(day.pred()) && time < self.end)
+        }
+    }
+}
+
+/// Check whether a reboot is allowed now, always if no window is configured
+pub fn is_open(windows: &[MaintenanceWindow], now: DateTime<Utc>) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
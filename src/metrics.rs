@@ -0,0 +1,242 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::get_current_timestamp;
+use crate::status::Status;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const PREFIX: &str = "openwrt_autoreboot";
+
+/// Latest figures and counters of one router
+#[derive(Default)]
+struct RouterMetrics {
+    host: String,
+    status: Option<Status>,
+    polls_succeeded: u64,
+    polls_failed: u64,
+    last_poll: Option<u64>,
+    reboots_issued: u64,
+    reboots_verified: u64,
+}
+
+/// Per router metrics, rendered in Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    routers: Mutex<BTreeMap<String, RouterMetrics>>,
+}
+
+impl Metrics {
+    fn update<F: FnOnce(&mut RouterMetrics)>(&self, name: &str, f: F) {
+        let mut routers = self.routers.lock().unwrap();
+        f(routers.entry(name.to_string()).or_default())
+    }
+
+    /// Export router before its first poll
+    pub fn register(&self, name: &str, host: &str) {
+        self.update(name, |router| router.host = host.to_string())
+    }
+
+    /// Record poll result, keep the last status if poll failed
+    pub fn record_poll(&self, name: &str, status: Option<&Status>) {
+        self.update(name, |router| {
+            router.last_poll = Some(get_current_timestamp());
+            match status {
+                Some(status) => {
+                    router.polls_succeeded += 1;
+                    router.status = Some(status.clone());
+                }
+                None => router.polls_failed += 1,
+            }
+        })
+    }
+
+    pub fn record_reboot(&self, name: &str) {
+        self.update(name, |router| router.reboots_issued += 1)
+    }
+
+    pub fn record_verified(&self, name: &str) {
+        self.update(name, |router| router.reboots_verified += 1)
+    }
+
+    pub fn render(&self) -> String {
+        let routers = self.routers.lock().unwrap();
+        let mut families: Vec<(&str, &str, &str, Vec<String>)> = vec![
+            ("cpu_usage_percent", "gauge", "Last cpu usage", vec![]),
+            ("load1", "gauge", "Last 1 minute load average", vec![]),
+            ("load5", "gauge", "Last 5 minutes load average", vec![]),
+            ("load15", "gauge", "Last 15 minutes load average", vec![]),
+            ("memory_bytes", "gauge", "Last memory figures", vec![]),
+            ("uptime_seconds", "gauge", "Last uptime", vec![]),
+            ("polls_total", "counter", "Polls by result", vec![]),
+            (
+                "last_poll_timestamp_seconds",
+                "gauge",
+                "Time of the last poll",
+                vec![],
+            ),
+            (
+                "reboots_issued_total",
+                "counter",
+                "Reboot calls sent",
+                vec![],
+            ),
+            (
+                "reboots_verified_total",
+                "counter",
+                "Reboots that came back healthy",
+                vec![],
+            ),
+        ];
+        for (name, router) in routers.iter() {
+            let labels = format!(
+                "router=\"{}\",host=\"{}\"",
+                escape(name),
+                escape(&router.host)
+            );
+            let mut sample = |index: usize, extra: &str, value: String| {
+                families[index]
+                    .3
+                    .push(format!("{{{}{}}} {}", labels, extra, value));
+            };
+            if let Some(status) = &router.status {
+                if let Some(cpu) = status.cpu_usage {
+                    sample(0, "", cpu.to_string());
+                }
+                for (index, load) in status.load_avg.iter().take(3).enumerate() {
+                    sample(1 + index, "", load.to_string());
+                }
+                if let Some(memory) = &status.memory {
+                    for (kind, value) in &[
+                        ("total", memory.total),
+                        ("free", memory.free),
+                        ("shared", memory.shared),
+                        ("buffered", memory.buffered),
+                        ("available", memory.available),
+                    ] {
+                        sample(4, &format!(",kind=\"{}\"", kind), value.to_string());
+                    }
+                }
+                if let Some(uptime) = status.uptime {
+                    sample(5, "", uptime.to_string());
+                }
+            }
+            sample(6, ",result=\"success\"", router.polls_succeeded.to_string());
+            sample(6, ",result=\"failure\"", router.polls_failed.to_string());
+            if let Some(last_poll) = router.last_poll {
+                sample(7, "", last_poll.to_string());
+            }
+            sample(8, "", router.reboots_issued.to_string());
+            sample(9, "", router.reboots_verified.to_string());
+        }
+        let mut output = String::new();
+        for (name, kind, help, samples) in families {
+            if samples.is_empty() {
+                continue;
+            }
+            writeln!(output, "# HELP {}_{} {}", PREFIX, name, help).unwrap();
+            writeln!(output, "# TYPE {}_{} {}", PREFIX, name, kind).unwrap();
+            for sample in samples {
+                writeln!(output, "{}_{}{}", PREFIX, name, sample).unwrap();
+            }
+        }
+        output
+    }
+}
+
+/// Time a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest accepted request head in bytes
+const MAX_HEAD: usize = 8192;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Read the request head up to the blank line
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|x| x == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let size = stream.read(&mut buffer).await?;
+        if size == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buffer[..size]);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+async fn handle(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let head = tokio::time::timeout(READ_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let target = head.split_whitespace().nth(1).unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default();
+    let response = if path == "/metrics" {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answer `GET /metrics` on `listener` forever
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    if let Ok(address) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", address);
+    }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Accept metrics connection failed: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &metrics).await {
+                debug!("Serve metrics failed: {}", e);
+            }
+        });
+    }
+}
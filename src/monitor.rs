@@ -0,0 +1,616 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::backend::{self, RouterBackend};
+use crate::config::{Config, Server};
+use crate::error::{Error, Result};
+use crate::get_current_timestamp;
+use crate::maintenance;
+use crate::metrics::Metrics;
+use crate::notify::{Dispatcher, Event, Notification, Telegram};
+use crate::policy::HealthPolicy;
+use crate::probe;
+use crate::remediation::Action;
+use crate::state::StateStore;
+use crate::status::Status;
+use crate::verify::{self, Outcome};
+use chrono::Utc;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Resources shared by every monitor
+#[derive(Clone)]
+pub struct Context {
+    pub state: Arc<StateStore>,
+    pub notifier: Arc<Dispatcher>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Context {
+    pub fn new(state: StateStore, notifier: Dispatcher) -> Self {
+        Self {
+            state: Arc::new(state),
+            notifier: Arc::new(notifier),
+            metrics: Default::default(),
+        }
+    }
+}
+
+/// Poll one router and reboot it when the health policy says so
+pub struct Monitor {
+    server: Server,
+    backend: Box<dyn RouterBackend>,
+    policy: HealthPolicy,
+    context: Context,
+    /// Notification channels of this router only
+    notifier: Dispatcher,
+    logged_in: bool,
+    unreachable: bool,
+    /// A needed reboot is deferred or blocked and was already alerted
+    reboot_held: bool,
+    /// Samples of an earlier run are loaded into the policy
+    history_restored: bool,
+}
+
+impl Monitor {
+    pub fn new(
+        server: Server,
+        backend: Box<dyn RouterBackend>,
+        policy: HealthPolicy,
+        context: Context,
+    ) -> Self {
+        context
+            .metrics
+            .register(server.get_name(), server.get_host());
+        Self {
+            server,
+            backend,
+            policy,
+            context,
+            notifier: Default::default(),
+            logged_in: false,
+            unreachable: false,
+            reboot_held: false,
+            history_restored: false,
+        }
+    }
+
+    pub fn from_server(server: &Server, context: Context) -> anyhow::Result<Self> {
+        let mut monitor = Self::new(
+            server.clone(),
+            backend::from_server(server)?,
+            HealthPolicy::from(server),
+            context,
+        );
+        if let Some(telegram) = &server.telegram {
+            monitor.notifier = Dispatcher::new(vec![Box::new(Telegram::new(telegram)?)]);
+        }
+        Ok(monitor)
+    }
+
+    async fn notify(&self, event: Event, status: Option<&Status>, reason: String) {
+        let notification = Notification::new(
+            event,
+            self.get_name(),
+            self.server.get_host(),
+            status,
+            reason,
+        );
+        futures::join!(
+            self.context.notifier.notify(&notification),
+            self.notifier.notify(&notification)
+        );
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.server.get_name()
+    }
+
+    /// Login, retry with exponential backoff if the router can not be reached
+    async fn login(&mut self) -> Result<()> {
+        let retry = &self.server.login_retry;
+        let mut delay = Duration::from_secs(retry.backoff);
+        let mut attempt = 1;
+        loop {
+            match self.backend.login().await {
+                Ok(_) => break,
+                Err(e @ Error::Request(_)) | Err(e @ Error::Connection(_))
+                    if attempt < retry.attempts =>
+                {
+                    warn!(
+                        "[{}] Login attempt {} failed: {}, retry in {}s",
+                        self.get_name(),
+                        attempt,
+                        e,
+                        delay.as_secs()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.logged_in = true;
+        Ok(())
+    }
+
+    /// Login again after the backend reported an expired session
+    async fn relogin(&mut self) -> Result<()> {
+        info!("[{}] Session expired, login again", self.get_name());
+        self.logged_in = false;
+        self.login().await
+    }
+
+    async fn fetch_status(&mut self) -> Result<Status> {
+        if !self.logged_in {
+            self.login().await?;
+        }
+        match self.backend.fetch_status().await {
+            Err(Error::SessionExpired) => {
+                self.relogin().await?;
+                self.backend.fetch_status().await
+            }
+            ret => ret,
+        }
+    }
+
+    async fn backend_reboot(&mut self, dry_run: bool) -> Result<()> {
+        match self.backend.reboot(dry_run).await {
+            Err(Error::SessionExpired) => {
+                self.relogin().await?;
+                self.backend.reboot(dry_run).await
+            }
+            ret => ret,
+        }
+    }
+
+    async fn backend_remediate(&mut self, action: &Action, dry_run: bool) -> Result<()> {
+        match self.backend.remediate(action, dry_run).await {
+            Err(Error::SessionExpired) => {
+                self.relogin().await?;
+                self.backend.remediate(action, dry_run).await
+            }
+            ret => ret,
+        }
+    }
+
+    /// Fetch status and add probe results
+    async fn poll(&mut self) -> Result<Status> {
+        let mut status = self.fetch_status().await?;
+        if !self.server.probes.is_empty() {
+            status.extra = probe::run_all(self.get_name(), &self.server.probes).await;
+            let router = probe::run_on_router(
+                self.server.get_name(),
+                &self.server.probes,
+                self.backend.as_mut(),
+            )
+            .await;
+            status.extra.extend(router);
+        }
+        Ok(status)
+    }
+
+    pub async fn check(&mut self) -> Result<()> {
+        let status = self.poll().await;
+        self.context
+            .metrics
+            .record_poll(self.get_name(), status.as_ref().ok());
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                let unreachable = matches!(
+                    e,
+                    Error::StatusUnreachable(_)
+                        | Error::Request(_)
+                        | Error::Connection(_)
+                        | Error::Timeout(_)
+                );
+                // Only notify when router turns unreachable
+                if unreachable && !self.unreachable {
+                    self.notify(Event::RouterUnreachable, None, e.to_string())
+                        .await;
+                }
+                self.unreachable = unreachable;
+                return Err(e);
+            }
+        };
+        self.unreachable = false;
+        if let (Some(uptime), Some(memory)) = (status.uptime, &status.memory) {
+            debug!(
+                "[{}] Uptime {}s, memory available {} of {} KiB",
+                self.get_name(),
+                uptime,
+                memory.available / 1024,
+                memory.total / 1024
+            );
+        }
+        if !self.history_restored {
+            let history = self.context.state.history(self.get_name()).await;
+            self.policy.restore(history);
+            self.history_restored = true;
+        }
+        let timestamp = get_current_timestamp();
+        let decision = self
+            .policy
+            .evaluate(self.server.get_name(), &status, timestamp);
+        self.save_history().await;
+        if !decision.reboot {
+            return match self.preventive_reason(&status, timestamp).await {
+                Some(reason) => self.reboot(&status, reason, Event::PreventiveReboot).await,
+                None => {
+                    self.reboot_held = false;
+                    Ok(())
+                }
+            };
+        }
+        let reasons = decision.reasons.join(", ");
+        let mut status = status;
+        for action in self.server.remediation.steps() {
+            if action == Action::Reboot {
+                return self.reboot(&status, reasons, Event::ThresholdCrossed).await;
+            }
+            status = match self.remediate(&action, status, &reasons).await {
+                Some(status) => status,
+                None => return Ok(()),
+            };
+        }
+        error!(
+            "[{}] Still unhealthy after every remediation step: {}",
+            self.get_name(),
+            reasons
+        );
+        Ok(())
+    }
+
+    async fn save_history(&self) {
+        self.context
+            .state
+            .record_history(self.get_name(), self.policy.history())
+            .await;
+    }
+
+    /// Reason of a scheduled or uptime based reboot of a healthy router, if due
+    async fn preventive_reason(&self, status: &Status, timestamp: u64) -> Option<String> {
+        let max_uptime = self.server.max_uptime_days * 24 * 60 * 60;
+        match status.uptime {
+            Some(uptime) if max_uptime > 0 && uptime > max_uptime => {
+                return Some(format!(
+                    "uptime {}d > {}d",
+                    uptime / 24 / 60 / 60,
+                    self.server.max_uptime_days
+                ));
+            }
+            _ => {}
+        }
+        let schedule = self.server.schedule.as_ref()?;
+        let state = &self.context.state;
+        let checked = state.schedule_checked(self.get_name()).await;
+        let since = match checked {
+            Some(since) => since,
+            None => {
+                // Start counting from the first poll
+                state
+                    .record_schedule_checked(self.get_name(), timestamp)
+                    .await;
+                return None;
+            }
+        };
+        // Stays due until a reboot is issued, see `reboot`
+        if schedule.due(since, timestamp) {
+            Some(format!("scheduled reboot ({})", schedule.cron))
+        } else {
+            None
+        }
+    }
+
+    /// Run one remediation step, return the latest status if the router is still unhealthy
+    async fn remediate(
+        &mut self,
+        action: &Action,
+        status: Status,
+        reasons: &str,
+    ) -> Option<Status> {
+        if self.server.dry_run {
+            warn!(
+                "[{}] Would run {} now (dry run): {}",
+                self.get_name(),
+                action,
+                reasons
+            );
+            match self.backend_remediate(action, true).await {
+                Ok(_) => info!("[{}] {} is possible, skip it", self.get_name(), action),
+                Err(e) => warn!("[{}] {} is not possible: {}", self.get_name(), action, e),
+            }
+            return Some(status);
+        }
+        warn!("[{}] Running {}: {}", self.get_name(), action, reasons);
+        if let Err(e) = self.backend_remediate(action, false).await {
+            error!("[{}] {} failed: {}, escalating", self.get_name(), action, e);
+            self.notify(
+                Event::RemediationApplied,
+                Some(&status),
+                format!("{} failed: {}; escalating", action, e),
+            )
+            .await;
+            return Some(status);
+        }
+        tokio::time::sleep(Duration::from_secs(self.server.remediation.delay)).await;
+        let after = match self.poll().await {
+            Ok(after) => after,
+            Err(e) => {
+                warn!(
+                    "[{}] Check after {} failed: {}, escalating",
+                    self.get_name(),
+                    action,
+                    e
+                );
+                self.notify(
+                    Event::RemediationApplied,
+                    Some(&status),
+                    format!("{}; check after it failed: {}; escalating", action, e),
+                )
+                .await;
+                return Some(status);
+            }
+        };
+        if self.policy.is_healthy(&after) {
+            info!("[{}] Healthy again after {}", self.get_name(), action);
+            self.notify(
+                Event::RemediationApplied,
+                Some(&after),
+                format!("{}; {}; router recovered", reasons, action),
+            )
+            .await;
+            self.policy.reset();
+            self.save_history().await;
+            return None;
+        }
+        warn!(
+            "[{}] Still unhealthy after {}, escalating",
+            self.get_name(),
+            action
+        );
+        self.notify(
+            Event::RemediationApplied,
+            Some(&after),
+            format!("{}; {}; still unhealthy, escalating", reasons, action),
+        )
+        .await;
+        Some(after)
+    }
+
+    /// Check maintenance windows, an emergency rule match overrides them
+    fn maintenance_allowed(&self, status: &Status) -> bool {
+        let windows = self.server.maintenance.as_deref().unwrap_or_default();
+        if maintenance::is_open(windows, Utc::now()) {
+            return true;
+        }
+        let emergency = match &self.server.emergency {
+            Some(emergency) => emergency,
+            None => return false,
+        };
+        let mut reasons = Vec::new();
+        if !emergency.evaluate(self.get_name(), status, &mut reasons) {
+            return false;
+        }
+        warn!(
+            "[{}] Emergency rule matched ({}), ignore maintenance window",
+            self.get_name(),
+            reasons.join(", ")
+        );
+        true
+    }
+
+    /// Reboot unless blocked by cooldown or budget, then verify.
+    /// `event` is the alert sent before the reboot, also when it is held back.
+    async fn reboot(&mut self, status: &Status, reasons: String, event: Event) -> Result<()> {
+        let timestamp = get_current_timestamp();
+        let blocked = self
+            .context
+            .state
+            .check_reboot(
+                self.get_name(),
+                self.server.cooldown,
+                self.server.max_reboots_per_day,
+                timestamp,
+            )
+            .await;
+        let deferred = !self.maintenance_allowed(status);
+        let action = match &blocked {
+            _ if deferred => "reboot deferred, outside maintenance window".to_string(),
+            Err(blocked) => format!("reboot blocked, {}", blocked),
+            Ok(_) if self.server.dry_run => "dry run, reboot skipped".to_string(),
+            Ok(_) => "rebooting".to_string(),
+        };
+        let held = deferred || blocked.is_err();
+        // Only alert once while the reboot is held back
+        if !(held && self.reboot_held) {
+            self.notify(event, Some(status), format!("{}; {}", reasons, action))
+                .await;
+        }
+        self.reboot_held = held;
+        if deferred {
+            warn!(
+                "[{}] Reboot needed ({}) but outside maintenance window, alert only",
+                self.get_name(),
+                reasons
+            );
+            return Ok(());
+        }
+        if let Err(blocked) = blocked {
+            error!(
+                "[{}] Reboot needed ({}) but {}, alert only",
+                self.get_name(),
+                reasons,
+                blocked
+            );
+            return Ok(());
+        }
+        if self.server.dry_run {
+            warn!(
+                "[{}] Would reboot now (dry run): {}",
+                self.get_name(),
+                reasons
+            );
+            self.backend_reboot(true).await?;
+            info!("[{}] Reboot is possible, skip reboot call", self.get_name());
+            return Ok(());
+        }
+        warn!(
+            "[{}] Should call reboot now, performance OpenWRT reboot: {}",
+            self.get_name(),
+            reasons
+        );
+        if let Err(e) = self.backend_reboot(false).await {
+            self.notify(Event::RebootFailed, Some(status), e.to_string())
+                .await;
+            return Err(e);
+        }
+        self.context
+            .state
+            .record_reboot(self.get_name(), timestamp)
+            .await;
+        // Any reboot also takes the place of the scheduled ones before it
+        if self.server.schedule.is_some() {
+            self.context
+                .state
+                .record_schedule_checked(self.get_name(), timestamp)
+                .await;
+        }
+        self.context.metrics.record_reboot(self.get_name());
+        self.notify(Event::RebootIssued, Some(status), reasons)
+            .await;
+        self.policy.reset();
+        self.save_history().await;
+        // Session is gone with the reboot
+        self.logged_in = false;
+        if self.server.verify.enabled {
+            self.verify(status).await;
+        }
+        Ok(())
+    }
+
+    async fn verify(&mut self, before: &Status) {
+        info!("[{}] Waiting for router to come back", self.get_name());
+        let (outcome, after) = verify::verify_reboot(
+            self.server.get_name(),
+            self.backend.as_mut(),
+            &self.policy,
+            &self.server.verify,
+            before,
+        )
+        .await;
+        self.logged_in = outcome != Outcome::DidNotReturn;
+        let reason = format!("verification outcome: {}", outcome);
+        if outcome == Outcome::Success {
+            info!("[{}] Reboot verified: {}", self.get_name(), outcome);
+            self.context.metrics.record_verified(self.get_name());
+            self.notify(Event::RebootVerified, after.as_ref(), reason)
+                .await;
+        } else {
+            error!(
+                "[{}] Reboot verification failed: {}",
+                self.get_name(),
+                outcome
+            );
+            self.notify(Event::RebootFailed, after.as_ref(), reason)
+                .await;
+        }
+        self.context
+            .state
+            .record_verification(self.get_name(), get_current_timestamp(), outcome)
+            .await;
+    }
+}
+
+/// Check every router once, return errors of failed checks in configuration order.
+/// A check taking longer than `timeout` is abandoned, so one stuck router can not hold up the others.
+pub async fn check_all(
+    monitors: &mut [Monitor],
+    concurrency: usize,
+    timeout: Duration,
+) -> Vec<Error> {
+    let mut failures = futures::stream::iter(monitors.iter_mut().enumerate())
+        .map(|(index, monitor)| async move {
+            let ret = tokio::time::timeout(timeout, monitor.check())
+                .await
+                .unwrap_or(Err(Error::Timeout(timeout.as_secs())));
+            match ret {
+                Ok(_) => {
+                    info!("[{}] Check finished", monitor.get_name());
+                    None
+                }
+                Err(e) => {
+                    error!("[{}] Check failed: {}", monitor.get_name(), e);
+                    Some((index, e))
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(futures::future::ready)
+        .collect::<Vec<_>>()
+        .await;
+    failures.sort_by_key(|(index, _)| *index);
+    failures.into_iter().map(|(_, e)| e).collect()
+}
+
+/// Poll routers on the configured interval until `shutdown` completes
+pub async fn run_daemon<F>(
+    config: &Config,
+    monitors: &mut [Monitor],
+    shutdown: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = std::io::Result<()>>,
+{
+    info!(
+        "Daemon started, polling {} routers every {}s (jitter {}s)",
+        monitors.len(),
+        config.interval,
+        config.jitter
+    );
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            failures = check_all(monitors, config.concurrency, Duration::from_secs(config.check_timeout)) => {
+                if !failures.is_empty() {
+                    warn!("{} of {} routers failed in this round", failures.len(), monitors.len());
+                }
+            }
+            ret = &mut shutdown => {
+                ret?;
+                break;
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(config.next_delay()) => {}
+            ret = &mut shutdown => {
+                ret?;
+                break;
+            }
+        }
+    }
+    info!("Received shutdown signal, exiting");
+    Ok(())
+}
@@ -0,0 +1,206 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::get_current_timestamp;
+use crate::status::Status;
+use async_trait::async_trait;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+pub mod smtp;
+pub mod telegram;
+pub mod webhook;
+
+pub use smtp::Smtp;
+pub use telegram::Telegram;
+pub use webhook::Webhook;
+
+/// Something worth telling a human about
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// Health policy decided the router should be rebooted
+    ThresholdCrossed,
+    RebootIssued,
+    /// Router came back healthy after reboot
+    RebootVerified,
+    /// Reboot call or post-reboot verification failed
+    RebootFailed,
+    RouterUnreachable,
+    /// A remediation step before reboot was run
+    RemediationApplied,
+    /// A scheduled or uptime based reboot of a healthy router is due
+    PreventiveReboot,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Event::ThresholdCrossed => "threshold crossed",
+            Event::RebootIssued => "reboot issued",
+            Event::RebootVerified => "reboot verified",
+            Event::RebootFailed => "reboot failed",
+            Event::RouterUnreachable => "router unreachable",
+            Event::RemediationApplied => "remediation applied",
+            Event::PreventiveReboot => "preventive reboot",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub router: String,
+    pub host: String,
+    /// Latest status sampled from the router, if any
+    pub metrics: Option<Status>,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+impl Notification {
+    pub fn new(
+        event: Event,
+        router: &str,
+        host: &str,
+        metrics: Option<&Status>,
+        reason: String,
+    ) -> Self {
+        Self {
+            event,
+            router: router.to_string(),
+            host: host.to_string(),
+            metrics: metrics.cloned(),
+            reason,
+            timestamp: get_current_timestamp(),
+        }
+    }
+}
+
+/// Fill placeholders of `template` from notification.
+///
+/// Placeholders: `{router}`, `{host}`, `{event}`, `{reason}`, `{cpu}`, `{load}`,
+/// `{uptime}` and `{mem_available}`.
+pub fn render(template: &str, notification: &Notification) -> String {
+    let status = notification.metrics.as_ref();
+    let unknown = || "n/a".to_string();
+    let cpu = status
+        .and_then(|x| x.cpu_usage)
+        .map(|x| format!("{}%", x))
+        .unwrap_or_else(unknown);
+    let load = status
+        .filter(|x| !x.load_avg.is_empty())
+        .map(|x| {
+            x.load_avg
+                .iter()
+                .map(|x| format!("{:.2}", x))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .unwrap_or_else(unknown);
+    let uptime = status
+        .and_then(|x| x.uptime)
+        .map(|x| format!("{}s", x))
+        .unwrap_or_else(unknown);
+    let mem_available = status
+        .and_then(|x| x.memory.as_ref())
+        .map(|x| format!("{} KiB", x.available / 1024))
+        .unwrap_or_else(unknown);
+    template
+        .replace("{router}", &notification.router)
+        .replace("{host}", &notification.host)
+        .replace("{event}", &notification.event.to_string())
+        .replace("{reason}", &notification.reason)
+        .replace("{cpu}", &cpu)
+        .replace("{load}", &load)
+        .replace("{uptime}", &uptime)
+        .replace("{mem_available}", &mem_available)
+}
+
+/// A channel notifications are delivered to
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether this channel wants to receive `event`
+    fn accepts(&self, event: Event) -> bool;
+
+    async fn send(&self, notification: &Notification) -> anyhow::Result<()>;
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct NotifyConfig {
+    #[serde(default)]
+    pub webhook: Vec<webhook::WebhookConfig>,
+    #[serde(default)]
+    pub smtp: Vec<smtp::SmtpConfig>,
+    /// Default Telegram settings of every server
+    pub telegram: Option<telegram::TelegramConfig>,
+}
+
+/// Deliver notifications to every configured channel
+#[derive(Default)]
+pub struct Dispatcher {
+    notifiers: Vec<Box<dyn Notifier>>,
+}
+
+impl Dispatcher {
+    pub fn new(notifiers: Vec<Box<dyn Notifier>>) -> Self {
+        Self { notifiers }
+    }
+
+    pub fn from_config(config: &NotifyConfig) -> anyhow::Result<Self> {
+        let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+        for webhook in &config.webhook {
+            notifiers.push(Box::new(Webhook::new(webhook.clone())?));
+        }
+        for smtp in &config.smtp {
+            notifiers.push(Box::new(Smtp::new(smtp.clone())?));
+        }
+        Ok(Self::new(notifiers))
+    }
+
+    /// Send to every channel concurrently, failures are logged only
+    pub async fn notify(&self, notification: &Notification) {
+        let sends = self
+            .notifiers
+            .iter()
+            .filter(|notifier| notifier.accepts(notification.event))
+            .map(|notifier| async move {
+                match notifier.send(notification).await {
+                    Ok(_) => debug!(
+                        "[{}] Sent {} notification to {}",
+                        notification.router,
+                        notification.event,
+                        notifier.name()
+                    ),
+                    Err(e) => error!(
+                        "[{}] Unable to send {} notification to {}: {:?}",
+                        notification.router,
+                        notification.event,
+                        notifier.name(),
+                        e
+                    ),
+                }
+            });
+        futures::future::join_all(sends).await;
+    }
+}
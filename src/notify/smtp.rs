@@ -0,0 +1,156 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::{Event, Notification, Notifier};
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade a plaintext connection with STARTTLS, port 587 by default
+    #[default]
+    Starttls,
+    /// Implicit TLS, port 465 by default
+    Tls,
+    /// Plaintext only, port 25 by default, for trusted local relays
+    None,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to the standard port of `tls` mode
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Only send these events, every event if empty
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+/// Send notification as plain text email
+pub struct Smtp {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    config: SmtpConfig,
+}
+
+impl Smtp {
+    pub fn new(config: SmtpConfig) -> anyhow::Result<Self> {
+        let mut builder = match config.tls {
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some(username) = &config.username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            ));
+        }
+        let transport = builder.timeout(Some(Duration::from_secs(30))).build();
+        Ok(Self {
+            transport,
+            from: config.from.parse()?,
+            to: config
+                .to
+                .iter()
+                .map(|x| x.parse())
+                .collect::<Result<_, _>>()?,
+            config,
+        })
+    }
+}
+
+/// Plain text body listing the sampled metrics
+fn render_body(notification: &Notification) -> String {
+    let mut lines = vec![
+        format!("Router: {}", notification.router),
+        format!("Host: {}", notification.host),
+        format!("Action: {}", notification.event),
+        format!("Reason: {}", notification.reason),
+    ];
+    if let Some(status) = &notification.metrics {
+        lines.push(match status.cpu_usage {
+            Some(cpu_usage) => format!("CPU usage: {}%", cpu_usage),
+            None => "CPU usage: not reported".to_string(),
+        });
+        let load_avg = status
+            .load_avg
+            .iter()
+            .map(|x| format!("{:.2}", x))
+            .collect::<Vec<_>>()
+            .join(" ");
+        lines.push(format!("Load average: {}", load_avg));
+        if let Some(uptime) = status.uptime {
+            lines.push(format!("Uptime: {}s", uptime));
+        }
+        if let Some(memory) = &status.memory {
+            lines.push(format!(
+                "Memory available: {} of {} KiB",
+                memory.available / 1024,
+                memory.total / 1024
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+#[async_trait]
+impl Notifier for Smtp {
+    fn name(&self) -> &str {
+        &self.config.host
+    }
+
+    fn accepts(&self, event: Event) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&event)
+    }
+
+    async fn send(&self, notification: &Notification) -> anyhow::Result<()> {
+        let mut builder = Message::builder().from(self.from.clone()).subject(format!(
+            "[openwrt-autoreboot] {}: {}",
+            notification.router, notification.event
+        ));
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(render_body(notification))?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::{render, Event, Notification, Notifier};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+const DEFAULT_TEMPLATE: &str =
+    "{router} ({host}): {event}\n{reason}\nCPU: {cpu}, load: {load}, memory available: {mem_available}";
+
+/// Telegram settings, every field set on a server overrides the global one
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct TelegramConfig {
+    pub token: Option<String>,
+    pub chat_id: Option<String>,
+    /// Bot API server, override for a self-hosted one
+    pub api_url: Option<String>,
+    /// Message template, see `notify::render` for placeholders
+    pub template: Option<String>,
+    /// Only send these events, every event if unset or empty
+    pub events: Option<Vec<Event>>,
+}
+
+impl TelegramConfig {
+    /// Fill fields missing here from `global`
+    pub fn merge(&self, global: &TelegramConfig) -> Self {
+        Self {
+            token: self.token.clone().or_else(|| global.token.clone()),
+            chat_id: self.chat_id.clone().or_else(|| global.chat_id.clone()),
+            api_url: self.api_url.clone().or_else(|| global.api_url.clone()),
+            template: self.template.clone().or_else(|| global.template.clone()),
+            events: self.events.clone().or_else(|| global.events.clone()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+/// Send notification through Bot API `sendMessage`
+pub struct Telegram {
+    client: reqwest::Client,
+    url: String,
+    chat_id: String,
+    template: String,
+    events: Vec<Event>,
+}
+
+impl Telegram {
+    pub fn new(config: &TelegramConfig) -> anyhow::Result<Self> {
+        let token = config
+            .token
+            .as_ref()
+            .ok_or_else(|| anyhow!("Telegram bot token is not set"))?;
+        let chat_id = config
+            .chat_id
+            .clone()
+            .ok_or_else(|| anyhow!("Telegram chat id is not set"))?;
+        let api_url = config.api_url.as_deref().unwrap_or(DEFAULT_API_URL);
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            url: format!("{}/bot{}/sendMessage", api_url.trim_end_matches('/'), token),
+            chat_id,
+            template: config
+                .template
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+            events: config.events.clone().unwrap_or_default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Telegram {
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    fn accepts(&self, event: Event) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    async fn send(&self, notification: &Notification) -> anyhow::Result<()> {
+        // The URL carries the bot token, keep it out of error messages
+        let response: ApiResponse = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "chat_id": self.chat_id,
+                "text": render(&self.template, notification),
+            }))
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?
+            .json()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        if !response.ok {
+            return Err(anyhow!(
+                "sendMessage failed: {}",
+                response.description.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+}
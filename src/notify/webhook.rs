@@ -0,0 +1,75 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::{Event, Notification, Notifier};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Only send these events, every event if empty
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+/// POST notification as JSON to an HTTP endpoint
+pub struct Webhook {
+    client: reqwest::Client,
+    config: WebhookConfig,
+    /// Host of the URL only, paths of chat webhooks carry secrets
+    name: String,
+}
+
+impl Webhook {
+    pub fn new(config: WebhookConfig) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(&config.url)?;
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            name: format!("webhook at {}", url.host_str().unwrap_or_default()),
+            config,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for Webhook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn accepts(&self, event: Event) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&event)
+    }
+
+    async fn send(&self, notification: &Notification) -> anyhow::Result<()> {
+        self.client
+            .post(&self.config.url)
+            .json(notification)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(reqwest::Error::without_url)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,531 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::Server;
+use crate::rule::Rule;
+use crate::status::Status;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+fn default_cpu_threshold() -> i32 {
+    20
+}
+
+fn default_load_threshold() -> f64 {
+    1.0
+}
+
+/// How the memory rule is combined with the cpu and load rule
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryMode {
+    /// Unhealthy on memory pressure or when cpu and load are exceeded
+    #[default]
+    Any,
+    /// Unhealthy on memory pressure while cpu and load are exceeded
+    All,
+    /// Unhealthy on memory pressure, cpu and load are ignored
+    Only,
+}
+
+/// Memory pressure rule, triggered when either limit is crossed
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct MemoryThreshold {
+    /// Available memory below this many MiB
+    pub available_mb: Option<u64>,
+    /// Available memory below this percent of total memory
+    pub available_percent: Option<f64>,
+    #[serde(default)]
+    pub mode: MemoryMode,
+}
+
+impl MemoryThreshold {
+    /// Return the reason if available memory is below a limit
+    fn pressure(&self, status: &Status) -> Option<String> {
+        let memory = status.memory.as_ref()?;
+        let available_mb = memory.available / 1024 / 1024;
+        if let Some(limit) = self.available_mb {
+            if available_mb < limit {
+                return Some(format!(
+                    "memory available {} MiB < {} MiB",
+                    available_mb, limit
+                ));
+            }
+        }
+        match self.available_percent {
+            Some(limit) if memory.total > 0 => {
+                let percent = memory.available as f64 * 100.0 / memory.total as f64;
+                if percent < limit {
+                    Some(format!("memory available {:.1}% < {:.1}%", percent, limit))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Threshold {
+    /// Cpu usage in percent
+    #[serde(default = "default_cpu_threshold")]
+    pub cpu: i32,
+    #[serde(default = "default_load_threshold")]
+    pub load1: f64,
+    #[serde(default = "default_load_threshold")]
+    pub load5: f64,
+    #[serde(default = "default_load_threshold")]
+    pub load15: f64,
+    pub memory: Option<MemoryThreshold>,
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Self {
+            cpu: default_cpu_threshold(),
+            load1: default_load_threshold(),
+            load5: default_load_threshold(),
+            load15: default_load_threshold(),
+            memory: None,
+        }
+    }
+}
+
+impl Threshold {
+    pub fn matches(&self, status: &Status) -> bool {
+        let pressure = || {
+            self.memory
+                .as_ref()
+                .and_then(|memory| memory.pressure(status))
+                .is_some()
+        };
+        match self.memory.as_ref().map(|memory| memory.mode) {
+            None => self.load_matches(status),
+            Some(MemoryMode::Any) => self.load_matches(status) || pressure(),
+            Some(MemoryMode::All) => self.load_matches(status) && pressure(),
+            Some(MemoryMode::Only) => pressure(),
+        }
+    }
+
+    fn load_matches(&self, status: &Status) -> bool {
+        let limits = [self.load1, self.load5, self.load15];
+        status.cpu_usage.map(|cpu| cpu > self.cpu).unwrap_or(true)
+            && status.load_avg.len() == limits.len()
+            && status
+                .load_avg
+                .iter()
+                .zip(limits.iter())
+                .all(|(value, limit)| value > limit)
+    }
+
+    /// Check status against thresholds, push the reason of every matched value
+    fn exceeded(&self, name: &str, status: &Status, reasons: &mut Vec<String>) -> bool {
+        let memory = match &self.memory {
+            Some(memory) => memory,
+            None => return self.load_exceeded(name, status, reasons),
+        };
+        let pressure = memory.pressure(status);
+        match &pressure {
+            Some(reason) => info!("[{}] Memory pressure: {}", name, reason),
+            None => info!("[{}] Available memory is above threshold", name),
+        }
+        let load = match memory.mode {
+            MemoryMode::Only => false,
+            MemoryMode::All if pressure.is_none() => return false,
+            _ => {
+                let mut load_reasons = Vec::new();
+                let load = self.load_exceeded(name, status, &mut load_reasons);
+                if load {
+                    reasons.append(&mut load_reasons);
+                }
+                load
+            }
+        };
+        let unhealthy = match memory.mode {
+            MemoryMode::Any => load || pressure.is_some(),
+            MemoryMode::All => load,
+            MemoryMode::Only => pressure.is_some(),
+        };
+        if unhealthy {
+            reasons.extend(pressure);
+        }
+        unhealthy
+    }
+
+    /// Check cpu and load average, push the reason of every matched value
+    fn load_exceeded(&self, name: &str, status: &Status, reasons: &mut Vec<String>) -> bool {
+        match status.cpu_usage {
+            Some(cpu_usage) if cpu_usage <= self.cpu => {
+                info!(
+                    "[{}] Current cpu usage is {}, there is nothing to do.",
+                    name, cpu_usage
+                );
+                return false;
+            }
+            Some(cpu_usage) => {
+                info!(
+                    "[{}] Current cpu usage is {} (threshold {}), checking load average",
+                    name, cpu_usage, self.cpu
+                );
+                reasons.push(format!("cpu usage {}% > {}%", cpu_usage, self.cpu));
+            }
+            None => info!(
+                "[{}] Cpu usage is not reported by backend, checking load average",
+                name
+            ),
+        }
+        let limits = [self.load1, self.load5, self.load15];
+        let names = ["load1", "load5", "load15"];
+        status.load_avg.len() == limits.len()
+            && status
+                .load_avg
+                .iter()
+                .zip(limits.iter().zip(names.iter()))
+                .all(|(value, (limit, load_name))| {
+                    if value > limit {
+                        info!(
+                            "[{}] Current load average value is {:.2} (threshold {:.2})",
+                            name, value, limit
+                        );
+                        reasons.push(format!("{} {:.2} > {:.2}", load_name, value, limit));
+                    }
+                    value > limit
+                })
+    }
+}
+
+fn default_window_size() -> usize {
+    1
+}
+
+/// Decide how many unhealthy samples are required before reboot
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Window {
+    /// Number of recent polls to keep
+    #[serde(default = "default_window_size")]
+    pub samples: usize,
+    /// Number of unhealthy polls among them required to reboot
+    #[serde(default = "default_window_size")]
+    pub required: usize,
+    /// Seconds the router must be unhealthy without interruption, 0 to disable
+    #[serde(default)]
+    pub duration: u64,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self {
+            samples: default_window_size(),
+            required: default_window_size(),
+            duration: 0,
+        }
+    }
+}
+
+impl Window {
+    fn samples(&self) -> usize {
+        self.samples.max(1)
+    }
+
+    fn required(&self) -> usize {
+        self.required.max(1).min(self.samples())
+    }
+}
+
+/// Recent health samples of a router, kept in the state file between runs
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct History {
+    samples: VecDeque<bool>,
+    unhealthy_since: Option<u64>,
+}
+
+impl History {
+    fn last_unhealthy(&self) -> bool {
+        self.samples.back().copied().unwrap_or(false)
+    }
+
+    fn push(&mut self, window: &Window, timestamp: u64, unhealthy: bool) {
+        if self.samples.len() >= window.samples() {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(unhealthy);
+        if !unhealthy {
+            self.unhealthy_since = None;
+        } else if self.unhealthy_since.is_none() {
+            self.unhealthy_since = Some(timestamp);
+        }
+    }
+
+    fn unhealthy_count(&self) -> usize {
+        self.samples.iter().filter(|x| **x).count()
+    }
+
+    fn triggered(&self, window: &Window, timestamp: u64) -> bool {
+        self.unhealthy_count() >= window.required()
+            && (window.duration == 0
+                || self
+                    .unhealthy_since
+                    .map(|since| timestamp.saturating_sub(since) >= window.duration)
+                    .unwrap_or(false))
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.unhealthy_since = None;
+    }
+}
+
+/// Result of a health policy evaluation
+pub struct Decision {
+    pub reboot: bool,
+    /// Human readable explanation of every matched rule
+    pub reasons: Vec<String>,
+}
+
+/// Decide whether a router should be rebooted from its recent status samples
+pub struct HealthPolicy {
+    threshold: Threshold,
+    recovery: Option<Threshold>,
+    rule: Option<Rule>,
+    window: Window,
+    history: History,
+}
+
+impl HealthPolicy {
+    pub fn new(threshold: Threshold, recovery: Option<Threshold>, window: Window) -> Self {
+        Self {
+            threshold,
+            recovery,
+            rule: None,
+            window,
+            history: Default::default(),
+        }
+    }
+
+    /// Decide with `rule` instead of the thresholds
+    pub fn with_rule(mut self, rule: Option<Rule>) -> Self {
+        self.rule = rule;
+        self
+    }
+
+    fn unhealthy(&self, name: &str, status: &Status, reasons: &mut Vec<String>) -> bool {
+        let rule = match &self.rule {
+            Some(rule) => rule,
+            None => return self.threshold.exceeded(name, status, reasons),
+        };
+        let unhealthy = rule.evaluate(name, status, reasons);
+        if !unhealthy {
+            info!("[{}] Rule {} not matched", name, rule);
+        }
+        unhealthy
+    }
+
+    /// Record a status sample and decide whether the router should be rebooted
+    pub fn evaluate(&mut self, name: &str, status: &Status, timestamp: u64) -> Decision {
+        let mut reasons = Vec::new();
+        let mut unhealthy = self.unhealthy(name, status, &mut reasons);
+        // Recovery thresholds only apply to the threshold rules
+        if !unhealthy && self.rule.is_none() && self.history.last_unhealthy() {
+            let recovery = self.recovery.as_ref().unwrap_or(&self.threshold);
+            if recovery.matches(status) {
+                info!(
+                    "[{}] Still above recovery threshold, keep unhealthy state",
+                    name
+                );
+                reasons.push("still above recovery threshold".to_string());
+                unhealthy = true;
+            }
+        }
+        self.history.push(&self.window, timestamp, unhealthy);
+        let reboot = self.history.triggered(&self.window, timestamp);
+        if unhealthy {
+            let summary = format!(
+                "unhealthy in {} of last {} polls",
+                self.history.unhealthy_count(),
+                self.history.samples.len()
+            );
+            if !reboot {
+                info!("[{}] {}, waiting for more samples", name, summary);
+            }
+            reasons.push(summary);
+            if let Some(since) = self.history.unhealthy_since {
+                reasons.push(format!(
+                    "unhealthy for {}s",
+                    timestamp.saturating_sub(since)
+                ));
+            }
+        }
+        Decision { reboot, reasons }
+    }
+
+    /// Check a single sample against thresholds, ignoring history
+    pub fn is_healthy(&self, status: &Status) -> bool {
+        match &self.rule {
+            Some(rule) => !rule.matches(status),
+            None => !self.threshold.matches(status),
+        }
+    }
+
+    /// Forget every sample, e.g. after the router is rebooted
+    pub fn reset(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Continue from samples of an earlier run
+    pub fn restore(&mut self, history: History) {
+        self.history = history;
+    }
+}
+
+impl From<&Server> for HealthPolicy {
+    fn from(server: &Server) -> Self {
+        Self::new(
+            server.threshold.clone(),
+            server.recovery.clone(),
+            server.window.clone(),
+        )
+        .with_rule(server.rule.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(cpu: i32, load: f64) -> Status {
+        Status {
+            cpu_usage: Some(cpu),
+            load_avg: vec![load; 3],
+            ..Default::default()
+        }
+    }
+
+    fn window(samples: usize, required: usize, duration: u64) -> Window {
+        Window {
+            samples,
+            required,
+            duration,
+        }
+    }
+
+    /// Feed `(timestamp, status)` samples, return the reboot decision of each
+    fn feed(policy: &mut HealthPolicy, samples: &[(u64, Status)]) -> Vec<bool> {
+        samples
+            .iter()
+            .map(|(timestamp, status)| policy.evaluate("test", status, *timestamp).reboot)
+            .collect()
+    }
+
+    #[test]
+    fn single_sample_decides() {
+        let mut policy = HealthPolicy::new(Default::default(), None, Default::default());
+        assert_eq!(
+            feed(&mut policy, &[(0, status(80, 2.5)), (1, status(5, 0.1))]),
+            [true, false]
+        );
+        // Cpu below threshold is enough to stay healthy
+        assert_eq!(feed(&mut policy, &[(2, status(5, 2.5))]), [false]);
+    }
+
+    #[test]
+    fn window_needs_n_of_m_unhealthy() {
+        let mut policy = HealthPolicy::new(Default::default(), None, window(5, 3, 0));
+        let (bad, good) = (status(80, 2.5), status(5, 0.1));
+        let samples = [
+            (0, bad.clone()),
+            (1, good.clone()),
+            (2, bad.clone()),
+            (3, good.clone()),
+            (4, bad.clone()),
+            // The first unhealthy sample leaves the window
+            (5, good.clone()),
+            (6, good.clone()),
+            (7, bad),
+        ];
+        assert_eq!(
+            feed(&mut policy, &samples),
+            [false, false, false, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn duration_needs_uninterrupted_unhealthy() {
+        let mut policy = HealthPolicy::new(Default::default(), None, window(1, 1, 600));
+        let (bad, good) = (status(80, 2.5), status(5, 0.1));
+        let samples = [
+            (0, bad.clone()),
+            (300, good),
+            (600, bad.clone()),
+            (900, bad.clone()),
+            (1200, bad),
+        ];
+        assert_eq!(
+            feed(&mut policy, &samples),
+            [false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn recovery_threshold_keeps_unhealthy() {
+        let threshold = Threshold {
+            load1: 2.0,
+            load5: 2.0,
+            load15: 2.0,
+            ..Default::default()
+        };
+        let recovery = Threshold {
+            cpu: 10,
+            ..Default::default()
+        };
+        let mut policy = HealthPolicy::new(threshold, Some(recovery), window(1, 1, 0));
+        // Between both thresholds only counts while already unhealthy
+        assert_eq!(feed(&mut policy, &[(0, status(50, 1.5))]), [false]);
+        assert_eq!(
+            feed(
+                &mut policy,
+                &[
+                    (1, status(50, 2.5)),
+                    (2, status(50, 1.5)),
+                    (3, status(50, 0.5))
+                ]
+            ),
+            [true, true, false]
+        );
+        assert_eq!(feed(&mut policy, &[(4, status(50, 1.5))]), [false]);
+    }
+
+    #[test]
+    fn reset_forgets_samples() {
+        let mut policy = HealthPolicy::new(Default::default(), None, window(3, 2, 0));
+        let bad = status(80, 2.5);
+        assert_eq!(feed(&mut policy, &[(0, bad.clone())]), [false]);
+        policy.reset();
+        assert_eq!(
+            feed(&mut policy, &[(1, bad.clone()), (2, bad)]),
+            [false, true]
+        );
+    }
+}
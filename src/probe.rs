@@ -0,0 +1,397 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::backend::RouterBackend;
+use crate::error::Error;
+use anyhow::{anyhow, bail};
+use futures::future::join_all;
+use log::{info, warn};
+use rand::Rng;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket};
+
+fn default_timeout() -> u64 {
+    5
+}
+
+/// What a probe checks
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Target {
+    /// ICMP echo with the system `ping` command
+    Ping { host: String },
+    /// TCP connect to `host:port`
+    Tcp { address: String },
+    /// HTTP GET answered with a success status
+    Http { url: String },
+    /// DNS query for an A record of `domain`, `server` port defaults to 53
+    Dns { server: String, domain: String },
+    /// Ping `host` from the router
+    #[serde(rename = "router_ping")]
+    RouterPing { host: String },
+    /// Resolve `domain` on the router
+    #[serde(rename = "router_nslookup")]
+    RouterNslookup { domain: String },
+    /// Traceroute to `host` from the router
+    #[serde(rename = "router_traceroute")]
+    RouterTraceroute { host: String },
+}
+
+impl Target {
+    /// Diagnostic run by the router backend, `None` for probes run from the monitoring host
+    pub fn diagnostic(&self) -> Option<(Diagnostic, &str)> {
+        match self {
+            Target::RouterPing { host } => Some((Diagnostic::Ping, host)),
+            Target::RouterNslookup { domain } => Some((Diagnostic::Nslookup, domain)),
+            Target::RouterTraceroute { host } => Some((Diagnostic::Traceroute, host)),
+            _ => None,
+        }
+    }
+}
+
+/// Network diagnostic run on the router itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Diagnostic {
+    Ping,
+    Nslookup,
+    Traceroute,
+}
+
+impl Diagnostic {
+    /// Command line run by the router, as LuCI runs it
+    pub fn command(self, target: &str) -> (&'static str, Vec<String>) {
+        let target = target.to_string();
+        match self {
+            Diagnostic::Ping => (
+                "/bin/ping",
+                vec!["-c".into(), "5".into(), "-W".into(), "1".into(), target],
+            ),
+            Diagnostic::Nslookup => ("/usr/bin/nslookup", vec![target]),
+            Diagnostic::Traceroute => (
+                "/usr/bin/traceroute",
+                vec![
+                    "-q".into(),
+                    "1".into(),
+                    "-w".into(),
+                    "1".into(),
+                    "-n".into(),
+                    target,
+                ],
+            ),
+        }
+    }
+
+    /// LuCI page of the diagnostic
+    pub fn page(self) -> &'static str {
+        match self {
+            Diagnostic::Ping => "diag_ping",
+            Diagnostic::Nslookup => "diag_nslookup",
+            Diagnostic::Traceroute => "diag_traceroute",
+        }
+    }
+}
+
+/// Connectivity check run from the monitoring host
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Probe {
+    pub name: String,
+    #[serde(flatten)]
+    pub target: Target,
+    /// Seconds before the probe fails
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Check the monitoring host's own link, other probes are ignored when it fails
+    #[serde(default)]
+    pub local: bool,
+}
+
+impl Probe {
+    /// Run probe, return its round trip time
+    pub async fn run(&self) -> anyhow::Result<Duration> {
+        let start = Instant::now();
+        tokio::time::timeout(Duration::from_secs(self.timeout), self.check())
+            .await
+            .map_err(|_| anyhow!("timed out after {}s", self.timeout))??;
+        Ok(start.elapsed())
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        match &self.target {
+            Target::Ping { host } => ping(host, self.timeout).await,
+            Target::Tcp { address } => {
+                TcpStream::connect(address).await?;
+                Ok(())
+            }
+            Target::Http { url } => {
+                reqwest::get(url).await?.error_for_status()?;
+                Ok(())
+            }
+            Target::Dns { server, domain } => dns_query(server, domain).await,
+            _ => bail!("runs on the router"),
+        }
+    }
+}
+
+async fn ping(host: &str, timeout: u64) -> anyhow::Result<()> {
+    let output = tokio::process::Command::new("ping")
+        .args(["-n", "-c", "1", "-W", &timeout.to_string(), host])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        bail!("no reply from {}", host);
+    }
+    Ok(())
+}
+
+fn dns_request(id: u16, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut request = Vec::with_capacity(512);
+    request.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    request.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid domain name {:?}", name);
+        }
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+    // Root label, type A, class IN
+    request.extend_from_slice(&[0, 0, 1, 0, 1]);
+    Ok(request)
+}
+
+/// Add port 53 to `server` unless it has a port: `1.1.1.1`, `::1`, `[::1]` and `dns.example`
+/// are queried on 53, `1.1.1.1:5353`, `[::1]:5353` and `dns.example:5353` on 5353
+fn dns_server_address(server: &str) -> String {
+    if server.parse::<SocketAddr>().is_ok() {
+        return server.to_string();
+    }
+    let host = server
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(server);
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 53).to_string(),
+        Err(_) if server.contains(':') => server.to_string(),
+        Err(_) => format!("{}:53", server),
+    }
+}
+
+async fn dns_query(server: &str, name: &str) -> anyhow::Result<()> {
+    let server = dns_server_address(server);
+    let server = tokio::net::lookup_host(&server)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("unable to resolve {}", server))?;
+    let socket = UdpSocket::bind(if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(server).await?;
+    let id = rand::thread_rng().gen::<u16>();
+    socket.send(&dns_request(id, name)?).await?;
+    let mut response = [0u8; 512];
+    loop {
+        let size = socket.recv(&mut response).await?;
+        if size < 12 || response[..2] != id.to_be_bytes() {
+            continue;
+        }
+        let rcode = response[3] & 0x0f;
+        if response[2] & 0x80 == 0 || rcode != 0 {
+            bail!("query for {} failed with rcode {}", name, rcode);
+        }
+        if u16::from_be_bytes([response[6], response[7]]) == 0 {
+            bail!("no answer for {}", name);
+        }
+        return Ok(());
+    }
+}
+
+/// Parse diagnostic output into metrics of probe `key`, return whether it succeeded
+fn parse_diagnostic(
+    diagnostic: Diagnostic,
+    output: &str,
+    key: &str,
+    metrics: &mut BTreeMap<String, f64>,
+) -> bool {
+    match diagnostic {
+        Diagnostic::Ping => {
+            static LOSS: OnceLock<Regex> = OnceLock::new();
+            static RTT: OnceLock<Regex> = OnceLock::new();
+            let loss = LOSS.get_or_init(|| Regex::new(r"([\d.]+)% packet loss").unwrap());
+            let rtt = RTT.get_or_init(|| Regex::new(r"= [\d.]+/([\d.]+)/[\d.]+").unwrap());
+            let loss = loss
+                .captures(output)
+                .and_then(|x| x[1].parse::<f64>().ok())
+                .unwrap_or(100.0);
+            metrics.insert(format!("{}.loss", key), loss);
+            if let Some(avg) = rtt.captures(output).and_then(|x| x[1].parse().ok()) {
+                metrics.insert(format!("{}.ms", key), avg);
+            }
+            loss < 100.0
+        }
+        Diagnostic::Nslookup => {
+            output.contains("Name:")
+                && !output.contains("can't resolve")
+                && !output.contains("can't find")
+        }
+        Diagnostic::Traceroute => {
+            let hops = output
+                .lines()
+                .filter(|line| {
+                    line.split_whitespace()
+                        .next()
+                        .map(|x| x.parse::<u32>().is_ok())
+                        .unwrap_or(false)
+                })
+                .collect::<Vec<_>>();
+            metrics.insert(format!("{}.hops", key), hops.len() as f64);
+            hops.last()
+                .map(|hop| hop.split_whitespace().skip(1).any(|x| x != "*"))
+                .unwrap_or(false)
+        }
+    }
+}
+
+/// Run router side probes through `backend`, return metrics for rules.
+///
+/// Every probe reports `probe.<name>` (1 on success, 0 on failure). `router_ping` also reports
+/// `probe.<name>.loss` (percent) and `probe.<name>.ms` (average round trip time),
+/// `router_traceroute` reports `probe.<name>.hops`.
+pub async fn run_on_router(
+    router: &str,
+    probes: &[Probe],
+    backend: &mut dyn RouterBackend,
+) -> BTreeMap<String, f64> {
+    let mut metrics = BTreeMap::new();
+    for probe in probes {
+        let (diagnostic, target) = match probe.target.diagnostic() {
+            Some(x) => x,
+            None => continue,
+        };
+        let key = format!("probe.{}", probe.name);
+        let diagnose = async {
+            match backend.diagnose(diagnostic, target).await {
+                Err(Error::SessionExpired) => {
+                    backend.login().await?;
+                    backend.diagnose(diagnostic, target).await
+                }
+                ret => ret,
+            }
+        };
+        let output = tokio::time::timeout(Duration::from_secs(probe.timeout), diagnose)
+            .await
+            .map_err(|_| anyhow!("timed out after {}s", probe.timeout))
+            .and_then(|x| x.map_err(anyhow::Error::from));
+        let ok = match output {
+            Ok(output) => parse_diagnostic(diagnostic, &output, &key, &mut metrics),
+            Err(e) => {
+                warn!("[{}] Router probe {} failed: {}", router, probe.name, e);
+                false
+            }
+        };
+        info!(
+            "[{}] Router probe {} {}",
+            router,
+            probe.name,
+            if ok { "succeeded" } else { "failed" }
+        );
+        metrics.insert(key, if ok { 1.0 } else { 0.0 });
+    }
+    metrics
+}
+
+/// Run probes of router `router`, return metrics for rules.
+///
+/// Every probe reports `probe.<name>` (1 on success, 0 on failure) and `probe.<name>.ms`,
+/// `probe.failed` counts failed probes. Nothing is reported when a local probe fails.
+pub async fn run_all(router: &str, probes: &[Probe]) -> BTreeMap<String, f64> {
+    let mut metrics = BTreeMap::new();
+    let (local, remote): (Vec<_>, Vec<_>) = probes
+        .iter()
+        .filter(|probe| probe.target.diagnostic().is_none())
+        .partition(|probe| probe.local);
+    for (probe, result) in local
+        .iter()
+        .zip(join_all(local.iter().map(|x| x.run())).await)
+    {
+        if let Err(e) = result {
+            warn!(
+                "[{}] Local probe {} failed: {}, ignore probes of this poll",
+                router, probe.name, e
+            );
+            return metrics;
+        }
+    }
+    let mut failed = 0;
+    for (probe, result) in remote
+        .iter()
+        .zip(join_all(remote.iter().map(|x| x.run())).await)
+    {
+        let key = format!("probe.{}", probe.name);
+        match result {
+            Ok(elapsed) => {
+                info!(
+                    "[{}] Probe {} succeeded in {}ms",
+                    router,
+                    probe.name,
+                    elapsed.as_millis()
+                );
+                metrics.insert(format!("{}.ms", key), elapsed.as_secs_f64() * 1000.0);
+                metrics.insert(key, 1.0);
+            }
+            Err(e) => {
+                warn!("[{}] Probe {} failed: {}", router, probe.name, e);
+                metrics.insert(key, 0.0);
+                failed += 1;
+            }
+        }
+    }
+    metrics.insert("probe.failed".to_string(), failed as f64);
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dns_server_gets_default_port() {
+        for (server, address) in [
+            ("1.1.1.1", "1.1.1.1:53"),
+            ("1.1.1.1:5353", "1.1.1.1:5353"),
+            ("::1", "[::1]:53"),
+            ("[::1]", "[::1]:53"),
+            ("[::1]:5353", "[::1]:5353"),
+            ("dns.example", "dns.example:53"),
+            ("dns.example:5353", "dns.example:5353"),
+        ] {
+            assert_eq!(dns_server_address(server), address);
+        }
+    }
+}
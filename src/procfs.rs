@@ -0,0 +1,152 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::error::{Error, Result};
+use crate::status::{Memory, Status};
+
+/// Cpu time counters from the first line of `/proc/stat`
+#[derive(Clone, Copy)]
+pub struct CpuTimes {
+    pub busy: u64,
+    pub total: u64,
+}
+
+impl CpuTimes {
+    /// Cpu usage in percent between `before` and this sample
+    pub fn usage_since(&self, before: &CpuTimes) -> i32 {
+        let total = self.total.saturating_sub(before.total);
+        if total == 0 {
+            return 0;
+        }
+        (self.busy.saturating_sub(before.busy) * 100 / total) as i32
+    }
+}
+
+fn invalid(file: &str) -> Error {
+    Error::UnexpectedStatus(format!("malformed {}", file))
+}
+
+pub fn parse_stat(stat: &str) -> Result<CpuTimes> {
+    let fields = stat
+        .lines()
+        .find(|line| line.starts_with("cpu "))
+        .ok_or_else(|| invalid("/proc/stat"))?
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|x| x.parse::<u64>().map_err(|_| invalid("/proc/stat")))
+        .collect::<Result<Vec<_>>>()?;
+    if fields.len() < 4 {
+        return Err(invalid("/proc/stat"));
+    }
+    let total = fields.iter().sum::<u64>();
+    // idle and iowait
+    let idle = fields[3] + fields.get(4).copied().unwrap_or(0);
+    Ok(CpuTimes {
+        busy: total - idle,
+        total,
+    })
+}
+
+pub fn parse_loadavg(loadavg: &str) -> Result<Vec<f64>> {
+    let load_avg = loadavg
+        .split_whitespace()
+        .take(3)
+        .map(|x| x.parse::<f64>().map_err(|_| invalid("/proc/loadavg")))
+        .collect::<Result<Vec<_>>>()?;
+    if load_avg.len() != 3 {
+        return Err(invalid("/proc/loadavg"));
+    }
+    Ok(load_avg)
+}
+
+/// Parse `/proc/meminfo`, figures are converted from KiB to bytes
+pub fn parse_meminfo(meminfo: &str) -> Result<Memory> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|x| x.split_whitespace().next()?.parse::<u64>().ok())
+            .map(|x| x * 1024)
+    };
+    let total = field("MemTotal").ok_or_else(|| invalid("/proc/meminfo"))?;
+    let free = field("MemFree").unwrap_or_default();
+    let buffered = field("Buffers").unwrap_or_default();
+    Ok(Memory {
+        total,
+        free,
+        shared: field("Shmem").unwrap_or_default(),
+        buffered,
+        // Kernels before 3.14 do not report MemAvailable
+        available: field("MemAvailable")
+            .unwrap_or_else(|| free + buffered + field("Cached").unwrap_or_default()),
+    })
+}
+
+pub fn parse_uptime(uptime: &str) -> Result<u64> {
+    uptime
+        .split_whitespace()
+        .next()
+        .and_then(|x| x.parse::<f64>().ok())
+        .map(|x| x as u64)
+        .ok_or_else(|| invalid("/proc/uptime"))
+}
+
+/// Build status from the contents of procfs files
+pub fn parse_status(
+    cpu_usage: Option<i32>,
+    loadavg: &str,
+    meminfo: &str,
+    uptime: &str,
+    conntrack: Option<&str>,
+) -> Result<Status> {
+    Ok(Status {
+        cpu_usage,
+        load_avg: parse_loadavg(loadavg)?,
+        memory: Some(parse_meminfo(meminfo)?),
+        uptime: Some(parse_uptime(uptime)?),
+        conntrack: conntrack.and_then(|x| x.trim().parse().ok()),
+        extra: Default::default(),
+    })
+}
+
+/// Shell command printing every procfs file of a status, parsed by `parse_snapshot`
+pub const SNAPSHOT_COMMAND: &str = "cat /proc/stat; echo @@; cat /proc/loadavg; echo @@; \
+    cat /proc/meminfo; echo @@; cat /proc/uptime; echo @@; \
+    cat /proc/sys/net/netfilter/nf_conntrack_count 2>/dev/null";
+
+/// Parse output of `SNAPSHOT_COMMAND`, cpu usage is computed since `before`
+pub fn parse_snapshot(output: &str, before: Option<CpuTimes>) -> Result<(CpuTimes, Status)> {
+    let sections: Vec<&str> = output.split("@@\n").collect();
+    let (stat, loadavg, meminfo, uptime, conntrack) = match sections[..] {
+        [stat, loadavg, meminfo, uptime, conntrack] => (stat, loadavg, meminfo, uptime, conntrack),
+        _ => {
+            return Err(Error::UnexpectedStatus(format!(
+                "expected 5 sections of procfs files, got {}",
+                sections.len()
+            )))
+        }
+    };
+    let now = parse_stat(stat)?;
+    let cpu_usage = before.map(|before| now.usage_since(&before));
+    let conntrack = Some(conntrack).filter(|x| !x.trim().is_empty());
+    let status = parse_status(cpu_usage, loadavg, meminfo, uptime, conntrack)?;
+    Ok((now, status))
+}
@@ -0,0 +1,128 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+
+/// One remediation step
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Action {
+    /// `restart service <name>`, run `/etc/init.d/<name> restart`
+    RestartService(String),
+    /// `reload service <name>`, run `/etc/init.d/<name> reload`
+    ReloadService(String),
+    /// `wifi reload`
+    WifiReload,
+    /// `network reload`
+    NetworkReload,
+    /// `reboot`
+    Reboot,
+}
+
+impl TryFrom<String> for Action {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let words = value.split_whitespace().collect::<Vec<_>>();
+        Ok(match words.as_slice() {
+            ["restart", "service", name] => Action::RestartService(name.to_string()),
+            ["reload", "service", name] => Action::ReloadService(name.to_string()),
+            ["wifi", "reload"] => Action::WifiReload,
+            ["network", "reload"] => Action::NetworkReload,
+            ["reboot"] => Action::Reboot,
+            _ => return Err(format!("unknown remediation action {:?}", value)),
+        })
+    }
+}
+
+impl From<Action> for String {
+    fn from(action: Action) -> Self {
+        action.to_string()
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::RestartService(name) => write!(f, "restart service {}", name),
+            Action::ReloadService(name) => write!(f, "reload service {}", name),
+            Action::WifiReload => write!(f, "wifi reload"),
+            Action::NetworkReload => write!(f, "network reload"),
+            Action::Reboot => write!(f, "reboot"),
+        }
+    }
+}
+
+impl Action {
+    /// ubus object, method and arguments that run this action
+    pub fn ubus_call(&self) -> (&'static str, &'static str, Value) {
+        match self {
+            Action::RestartService(name) => {
+                ("rc", "init", json!({ "name": name, "action": "restart" }))
+            }
+            Action::ReloadService(name) => {
+                ("rc", "init", json!({ "name": name, "action": "reload" }))
+            }
+            Action::WifiReload => (
+                "file",
+                "exec",
+                json!({ "command": "/sbin/wifi", "params": ["reload"] }),
+            ),
+            Action::NetworkReload => ("network", "reload", json!({})),
+            Action::Reboot => ("system", "reboot", json!({})),
+        }
+    }
+}
+
+fn default_delay() -> u64 {
+    30
+}
+
+/// Ordered steps tried on an unhealthy router, from the least disruptive one
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Remediation {
+    /// Steps after `reboot` are never reached, reboot only if empty
+    #[serde(default)]
+    pub steps: Vec<Action>,
+    /// Seconds to wait after a step before checking health again
+    #[serde(default = "default_delay")]
+    pub delay: u64,
+}
+
+impl Default for Remediation {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            delay: default_delay(),
+        }
+    }
+}
+
+impl Remediation {
+    pub fn steps(&self) -> Vec<Action> {
+        if self.steps.is_empty() {
+            return vec![Action::Reboot];
+        }
+        self.steps.clone()
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::status::{self, Status};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// Compare one named metric, see `Status::metric` for the names
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub metric: String,
+    /// Matches when the metric is greater than this value
+    pub above: Option<f64>,
+    /// Matches when the metric is less than this value
+    pub below: Option<f64>,
+}
+
+impl Condition {
+    fn accepts(&self, value: f64) -> bool {
+        self.above.map(|limit| value > limit).unwrap_or(true)
+            && self.below.map(|limit| value < limit).unwrap_or(true)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !status::is_metric(&self.metric) {
+            return Err(format!("unknown metric {:?}", self.metric));
+        }
+        if self.above.is_none() && self.below.is_none() {
+            return Err(format!(
+                "condition on {} needs `above` or `below`",
+                self.metric
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.above, self.below) {
+            (Some(above), Some(below)) => write!(f, "{} < {} < {}", above, self.metric, below),
+            (Some(above), None) => write!(f, "{} > {}", self.metric, above),
+            (None, Some(below)) => write!(f, "{} < {}", self.metric, below),
+            (None, None) => write!(f, "{} is reported", self.metric),
+        }
+    }
+}
+
+/// Tree of conditions deciding whether a router is unhealthy
+///
+/// A condition on a metric that is not reported is unknown rather than false,
+/// so neither it nor a `not` over it matches.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Rule {
+    All { all: Vec<Rule> },
+    Any { any: Vec<Rule> },
+    Not { not: Box<Rule> },
+    Condition(Condition),
+}
+
+impl Rule {
+    /// Check every condition has a known metric and a limit
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Rule::All { all: rules } | Rule::Any { any: rules } => {
+                rules.iter().try_for_each(Rule::validate)
+            }
+            Rule::Not { not } => not.validate(),
+            Rule::Condition(condition) => condition.validate(),
+        }
+    }
+
+    /// Check rule without logging
+    pub fn matches(&self, status: &Status) -> bool {
+        self.check(status) == Some(true)
+    }
+
+    /// Check rule, `None` when it depends on a metric that is not reported
+    fn check(&self, status: &Status) -> Option<bool> {
+        match self {
+            Rule::All { all } => {
+                let mut ret = Some(true);
+                for rule in all {
+                    match rule.check(status) {
+                        Some(false) => return Some(false),
+                        None => ret = None,
+                        Some(true) => {}
+                    }
+                }
+                ret
+            }
+            Rule::Any { any } => {
+                let mut ret = Some(false);
+                for rule in any {
+                    match rule.check(status) {
+                        Some(true) => return Some(true),
+                        None => ret = None,
+                        Some(false) => {}
+                    }
+                }
+                ret
+            }
+            Rule::Not { not } => not.check(status).map(|x| !x),
+            Rule::Condition(condition) => status
+                .metric(&condition.metric)
+                .map(|value| condition.accepts(value)),
+        }
+    }
+
+    /// Evaluate rule, push every matched condition to `reasons` if the rule matches
+    pub fn evaluate(&self, name: &str, status: &Status, reasons: &mut Vec<String>) -> bool {
+        self.evaluate_checked(name, status, reasons) == Some(true)
+    }
+
+    fn evaluate_checked(
+        &self,
+        name: &str,
+        status: &Status,
+        reasons: &mut Vec<String>,
+    ) -> Option<bool> {
+        let mut matched = Vec::new();
+        let ret = match self {
+            Rule::All { all } => {
+                let mut ret = Some(true);
+                for rule in all {
+                    match rule.evaluate_checked(name, status, &mut matched) {
+                        Some(false) => {
+                            ret = Some(false);
+                            break;
+                        }
+                        None => ret = None,
+                        Some(true) => {}
+                    }
+                }
+                ret
+            }
+            // Evaluate every branch to report every matched condition
+            Rule::Any { any } => {
+                let mut ret = Some(false);
+                for rule in any {
+                    match rule.evaluate_checked(name, status, &mut matched) {
+                        Some(true) => ret = Some(true),
+                        None if ret != Some(true) => ret = None,
+                        _ => {}
+                    }
+                }
+                ret
+            }
+            Rule::Not { not } => {
+                let ret = not
+                    .evaluate_checked(name, status, &mut Vec::new())
+                    .map(|x| !x);
+                if ret == Some(true) {
+                    matched.push(self.to_string());
+                }
+                ret
+            }
+            Rule::Condition(condition) => match status.metric(&condition.metric) {
+                Some(value) if condition.accepts(value) => {
+                    info!(
+                        "[{}] Condition {} matched ({} is {:.2})",
+                        name, condition, condition.metric, value
+                    );
+                    matched.push(format!("{} ({:.2})", condition, value));
+                    Some(true)
+                }
+                Some(value) => {
+                    info!(
+                        "[{}] Condition {} not matched ({} is {:.2})",
+                        name, condition, condition.metric, value
+                    );
+                    Some(false)
+                }
+                None => {
+                    info!(
+                        "[{}] Condition {} not matched, {} is not reported",
+                        name, condition, condition.metric
+                    );
+                    None
+                }
+            },
+        };
+        if ret == Some(true) {
+            reasons.append(&mut matched);
+        }
+        ret
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |rules: &[Rule]| {
+            rules
+                .iter()
+                .map(Rule::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            Rule::All { all } => write!(f, "all({})", join(all)),
+            Rule::Any { any } => write!(f, "any({})", join(any)),
+            Rule::Not { not } => write!(f, "not({})", not),
+            Rule::Condition(condition) => condition.fmt(f),
+        }
+    }
+}
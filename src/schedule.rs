@@ -0,0 +1,133 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// Names of days of week in crontab numbering, where both 0 and 7 are Sunday
+const WEEKDAYS: [&str; 8] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT", "SUN"];
+
+/// Rewrite a crontab day of week field with names, the cron crate counts from 1 for Sunday
+fn crontab_weekdays(field: &str) -> Result<String, String> {
+    if field.chars().any(|c| c.is_ascii_alphabetic()) || field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+    let number = |x: &str| match x.parse::<usize>() {
+        Ok(x) if x < WEEKDAYS.len() => Ok(x),
+        _ => Err(format!("invalid day of week {:?}", x)),
+    };
+    let mut days = [false; 7];
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, number(step)?.max(1)),
+            None => (item, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None if range == "*" => (0, 7),
+            // `n/step` runs from n to the end of the week
+            None if item.contains('/') => (number(range)?, 7),
+            None => (number(range)?, number(range)?),
+        };
+        for day in (first..=last).step_by(step) {
+            days[day % 7] = true;
+        }
+    }
+    if !days.contains(&true) {
+        return Err(format!("no day of week in {:?}", field));
+    }
+    Ok(days
+        .iter()
+        .zip(WEEKDAYS.iter())
+        .filter(|(on, _)| **on)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// Cron expression with five fields (minute to day of week), or six with leading seconds
+///
+/// Days of week are numbered like crontab: 0 or 7 is Sunday, 1 is Monday.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    expression: String,
+    schedule: cron::Schedule,
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        let mut fields: Vec<String> = expression.split_whitespace().map(String::from).collect();
+        if fields.len() == 5 {
+            fields.insert(0, "0".to_string());
+        }
+        if let Some(weekdays) = fields.get_mut(5) {
+            *weekdays = crontab_weekdays(weekdays)
+                .map_err(|e| format!("invalid cron expression {:?}: {}", expression, e))?;
+        }
+        let schedule = cron::Schedule::from_str(&fields.join(" "))
+            .map_err(|e| format!("invalid cron expression {:?}: {}", expression, e))?;
+        Ok(Self {
+            expression,
+            schedule,
+        })
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.expression
+    }
+}
+
+impl std::fmt::Display for Cron {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+/// Unconditional reboot on a cron schedule
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RebootSchedule {
+    pub cron: Cron,
+    /// IANA timezone the schedule is written in, UTC if unset
+    pub timezone: Option<Tz>,
+}
+
+impl RebootSchedule {
+    /// Check whether a scheduled time is after `since` and not after `now`
+    pub fn due(&self, since: u64, now: u64) -> bool {
+        let since = match Utc.timestamp_opt(since as i64, 0).single() {
+            Some(since) => since.with_timezone(&self.timezone.unwrap_or(Tz::UTC)),
+            None => return false,
+        };
+        self.cron
+            .schedule
+            .after(&since)
+            .next()
+            .map(|next| next.timestamp() as u64 <= now)
+            .unwrap_or(false)
+    }
+}
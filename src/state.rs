@@ -0,0 +1,217 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::policy::History;
+use crate::verify::Outcome;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// Seconds in the rolling window of the daily reboot budget
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Verification {
+    pub timestamp: u64,
+    pub outcome: Outcome,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct RouterState {
+    /// Timestamps of reboots issued in the last day, the latest one is always kept
+    #[serde(default)]
+    pub reboots: Vec<u64>,
+    /// Outcome of the latest post-reboot verification
+    pub last_verification: Option<Verification>,
+    /// Scheduled reboots up to this time are handled
+    pub schedule_checked: Option<u64>,
+    /// Health samples of the policy window
+    #[serde(default)]
+    pub history: History,
+}
+
+impl RouterState {
+    pub fn last_reboot(&self) -> Option<u64> {
+        self.reboots.iter().max().copied()
+    }
+
+    fn prune(&mut self, timestamp: u64) {
+        let last = self.last_reboot();
+        self.reboots
+            .retain(|x| timestamp.saturating_sub(*x) < DAY || Some(*x) == last);
+    }
+
+    fn reboots_in_day(&self, timestamp: u64) -> usize {
+        self.reboots
+            .iter()
+            .filter(|x| timestamp.saturating_sub(**x) < DAY)
+            .count()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    #[serde(default)]
+    routers: HashMap<String, RouterState>,
+}
+
+/// Reason why a reboot is not allowed right now
+pub enum Blocked {
+    /// Seconds left until cooldown ends
+    Cooldown(u64),
+    /// Number of reboots already issued in the last day
+    Budget(usize),
+}
+
+impl std::fmt::Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Blocked::Cooldown(left) => write!(f, "still in cooldown for {}s", left),
+            Blocked::Budget(count) => write!(f, "daily budget used up by {} reboots", count),
+        }
+    }
+}
+
+/// Reboot history of every router, persisted to a JSON file
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl StateStore {
+    /// Load state from `path`, start empty if the file does not exist yet
+    pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let state = match tokio::fs::read_to_string(path).await {
+            Ok(context) => serde_json::from_str(&context)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// State kept in memory only
+    pub fn memory() -> Self {
+        Self {
+            path: None,
+            state: Default::default(),
+        }
+    }
+
+    async fn save(&self, state: &State) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = async {
+            let temp = path.with_extension("tmp");
+            tokio::fs::write(&temp, serde_json::to_vec_pretty(state)?).await?;
+            tokio::fs::rename(&temp, path).await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = result {
+            error!("Unable to save state to {}: {:?}", path.display(), e);
+        }
+    }
+
+    /// Check cooldown and daily budget of router `name`
+    pub async fn check_reboot(
+        &self,
+        name: &str,
+        cooldown: u64,
+        max_per_day: usize,
+        timestamp: u64,
+    ) -> Result<(), Blocked> {
+        let state = self.state.lock().await;
+        let router = match state.routers.get(name) {
+            Some(router) => router,
+            None => return Ok(()),
+        };
+        if let Some(last) = router.last_reboot() {
+            let elapsed = timestamp.saturating_sub(last);
+            if elapsed < cooldown {
+                return Err(Blocked::Cooldown(cooldown - elapsed));
+            }
+        }
+        let count = router.reboots_in_day(timestamp);
+        if max_per_day > 0 && count >= max_per_day {
+            return Err(Blocked::Budget(count));
+        }
+        Ok(())
+    }
+
+    /// Record a reboot of router `name`
+    pub async fn record_reboot(&self, name: &str, timestamp: u64) {
+        let mut state = self.state.lock().await;
+        let router = state.routers.entry(name.to_string()).or_default();
+        router.reboots.push(timestamp);
+        router.prune(timestamp);
+        self.save(&state).await;
+    }
+
+    /// Time up to which scheduled reboots of router `name` are handled
+    pub async fn schedule_checked(&self, name: &str) -> Option<u64> {
+        let state = self.state.lock().await;
+        state.routers.get(name).and_then(|x| x.schedule_checked)
+    }
+
+    /// Mark scheduled reboots of router `name` up to `timestamp` as handled
+    pub async fn record_schedule_checked(&self, name: &str, timestamp: u64) {
+        let mut state = self.state.lock().await;
+        let router = state.routers.entry(name.to_string()).or_default();
+        router.schedule_checked = Some(timestamp);
+        self.save(&state).await;
+    }
+
+    /// Health samples of router `name` recorded by an earlier run
+    pub async fn history(&self, name: &str) -> History {
+        let state = self.state.lock().await;
+        state
+            .routers
+            .get(name)
+            .map(|x| x.history.clone())
+            .unwrap_or_default()
+    }
+
+    /// Keep the health samples of router `name`, saved only when changed
+    pub async fn record_history(&self, name: &str, history: &History) {
+        let mut state = self.state.lock().await;
+        let router = state.routers.entry(name.to_string()).or_default();
+        if router.history == *history {
+            return;
+        }
+        router.history = history.clone();
+        self.save(&state).await;
+    }
+
+    /// Record the outcome of a post-reboot verification of router `name`
+    pub async fn record_verification(&self, name: &str, timestamp: u64, outcome: Outcome) {
+        let mut state = self.state.lock().await;
+        let router = state.routers.entry(name.to_string()).or_default();
+        router.last_verification = Some(Verification { timestamp, outcome });
+        self.save(&state).await;
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// OpenWRT reports load average as fixed-point integer
+pub const LOAD_SCALE: f64 = 65536.0;
+
+/// Metric names of `Status::metric`, besides the `probe.` metrics
+pub const METRICS: &[&str] = &[
+    "cpu",
+    "load1",
+    "load5",
+    "load15",
+    "mem_total",
+    "mem_free",
+    "mem_available",
+    "mem_available_percent",
+    "uptime",
+    "conntrack",
+];
+
+/// Whether `name` can be looked up with `Status::metric`
+pub fn is_metric(name: &str) -> bool {
+    METRICS.contains(&name) || name.starts_with("probe.")
+}
+
+/// Memory figures in bytes
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(from = "RawMemory")]
+pub struct Memory {
+    pub total: u64,
+    pub free: u64,
+    pub shared: u64,
+    pub buffered: u64,
+    pub available: u64,
+}
+
+/// Memory as reported by the router, older firmware has no `available`
+#[derive(Deserialize)]
+struct RawMemory {
+    #[serde(default)]
+    total: u64,
+    #[serde(default)]
+    free: u64,
+    #[serde(default)]
+    shared: u64,
+    #[serde(default)]
+    buffered: u64,
+    available: Option<u64>,
+}
+
+impl From<RawMemory> for Memory {
+    fn from(raw: RawMemory) -> Self {
+        Memory {
+            total: raw.total,
+            free: raw.free,
+            shared: raw.shared,
+            buffered: raw.buffered,
+            available: raw.available.unwrap_or(raw.free + raw.buffered),
+        }
+    }
+}
+
+/// Health figures of a router, as reported by a backend
+#[derive(Clone, Default, Serialize)]
+pub struct Status {
+    /// Not every backend reports cpu usage
+    pub cpu_usage: Option<i32>,
+    /// 1, 5 and 15 minutes load average
+    pub load_avg: Vec<f64>,
+    pub memory: Option<Memory>,
+    pub uptime: Option<u64>,
+    /// Number of tracked connections
+    pub conntrack: Option<u64>,
+    /// Additional named metrics, e.g. probe results
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, f64>,
+}
+
+impl Status {
+    /// Look up a metric by name, as used by rules
+    ///
+    /// Memory metrics are in MiB, except `mem_available_percent`.
+    pub fn metric(&self, name: &str) -> Option<f64> {
+        let mib = |x: u64| x as f64 / 1024.0 / 1024.0;
+        match name {
+            "cpu" => self.cpu_usage.map(f64::from),
+            "load1" => self.load_avg.first().copied(),
+            "load5" => self.load_avg.get(1).copied(),
+            "load15" => self.load_avg.get(2).copied(),
+            "mem_total" => self.memory.as_ref().map(|x| mib(x.total)),
+            "mem_free" => self.memory.as_ref().map(|x| mib(x.free)),
+            "mem_available" => self.memory.as_ref().map(|x| mib(x.available)),
+            "mem_available_percent" => self
+                .memory
+                .as_ref()
+                .filter(|x| x.total > 0)
+                .map(|x| x.available as f64 * 100.0 / x.total as f64),
+            "uptime" => self.uptime.map(|x| x as f64),
+            "conntrack" => self.conntrack.map(|x| x as f64),
+            _ => self.extra.get(name).copied(),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::backend::RouterBackend;
+use crate::config::Verify;
+use crate::policy::HealthPolicy;
+use crate::status::Status;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Result of waiting for a router to come back after reboot
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Router came back with reset uptime and healthy metrics
+    Success,
+    /// Router came back, but its metrics are still above thresholds
+    Unhealthy,
+    /// Router kept answering and its uptime was not reset
+    NeverRebooted,
+    /// Router did not answer again before the deadline
+    DidNotReturn,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Outcome::Success => "success",
+            Outcome::Unhealthy => "unhealthy",
+            Outcome::NeverRebooted => "never rebooted",
+            Outcome::DidNotReturn => "did not return",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Wait for the router to go down and come back, then check uptime and health.
+///
+/// The backend is logged in again when the router answers.
+pub async fn verify_reboot(
+    name: &str,
+    backend: &mut dyn RouterBackend,
+    policy: &HealthPolicy,
+    config: &Verify,
+    before: &Status,
+) -> (Outcome, Option<Status>) {
+    let interval = Duration::from_secs(config.interval.max(1));
+
+    // Every attempt is bounded by the time left, a router that is down may never answer
+    let deadline = Instant::now() + Duration::from_secs(config.down_timeout);
+    let mut went_down = false;
+    while let Some(left) = time_left(deadline) {
+        tokio::time::sleep(interval.min(left)).await;
+        let left = time_left(deadline).unwrap_or_default();
+        match tokio::time::timeout(left, backend.fetch_status()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                info!("[{}] Router went down after reboot ({})", name, e);
+                went_down = true;
+                break;
+            }
+            Err(_) => {
+                info!("[{}] Router went down after reboot (no answer)", name);
+                went_down = true;
+                break;
+            }
+        }
+    }
+    if !went_down {
+        info!(
+            "[{}] Router did not become unreachable in {}s",
+            name, config.down_timeout
+        );
+    }
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(config.up_timeout);
+    let status = loop {
+        let left = match time_left(deadline) {
+            Some(left) => left,
+            None => return (Outcome::DidNotReturn, None),
+        };
+        tokio::time::sleep(interval.min(left)).await;
+        let left = time_left(deadline).unwrap_or_default();
+        let attempt = async {
+            backend.login().await?;
+            backend.fetch_status().await
+        };
+        match tokio::time::timeout(left, attempt).await {
+            Ok(Ok(status)) => break status,
+            Ok(Err(e)) => debug!("[{}] Router is not back yet: {}", name, e),
+            Err(_) => debug!("[{}] Router is not back yet: no answer", name),
+        }
+    };
+    info!(
+        "[{}] Router answered again after {}s",
+        name,
+        start.elapsed().as_secs()
+    );
+
+    let outcome = match (before.uptime, status.uptime) {
+        (Some(before), Some(after)) if after >= before => Outcome::NeverRebooted,
+        (Some(_), Some(_)) => check_health(policy, &status),
+        _ if went_down => check_health(policy, &status),
+        _ => Outcome::NeverRebooted,
+    };
+    (outcome, Some(status))
+}
+
+/// Time until `deadline`, `None` once it passed
+fn time_left(deadline: Instant) -> Option<Duration> {
+    Some(deadline.saturating_duration_since(Instant::now())).filter(|left| !left.is_zero())
+}
+
+fn check_health(policy: &HealthPolicy, status: &Status) -> Outcome {
+    if policy.is_healthy(status) {
+        Outcome::Success
+    } else {
+        Outcome::Unhealthy
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::path::Path;
+use std::process::Output;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockBuilder, MockServer, Request, ResponseTemplate};
+
+pub const SESSION: &str = "0123456789abcdef0123456789abcdef";
+
+/// Match a JSON-RPC request by ubus session, object and method
+struct UbusCall {
+    session: Option<&'static str>,
+    object: &'static str,
+    method: &'static str,
+}
+
+impl wiremock::Match for UbusCall {
+    fn matches(&self, request: &Request) -> bool {
+        let body: Value = match serde_json::from_slice(&request.body) {
+            Ok(body) => body,
+            Err(_) => return false,
+        };
+        let params = &body["params"];
+        self.session.map(|x| params[0] == x).unwrap_or(true)
+            && params[1] == self.object
+            && params[2] == self.method
+    }
+}
+
+pub fn ubus_call(
+    session: Option<&'static str>,
+    object: &'static str,
+    function: &'static str,
+) -> MockBuilder {
+    Mock::given(method("POST"))
+        .and(path("/ubus"))
+        .and(UbusCall {
+            session,
+            object,
+            method: function,
+        })
+}
+
+pub fn result(data: Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({"jsonrpc": "2.0", "id": 1, "result": data}))
+}
+
+pub fn system_info(load: f64) -> Value {
+    system_info_with_uptime(load, 3600)
+}
+
+pub fn system_info_with_uptime(load: f64, uptime: u64) -> Value {
+    let load = (load * 65536.0) as u64;
+    json!([0, {
+        "localtime": 1625000000,
+        "uptime": uptime,
+        "load": [load, load, load],
+        "memory": {
+            "total": 128 * 1024 * 1024,
+            "free": 64 * 1024 * 1024,
+            "shared": 1024 * 1024,
+            "buffered": 4 * 1024 * 1024,
+            "available": 70 * 1024 * 1024,
+        },
+    }])
+}
+
+pub async fn mock_login(server: &MockServer) {
+    ubus_call(None, "session", "login")
+        .respond_with(result(json!([0, {"ubus_rpc_session": SESSION}])))
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+/// Ubus router polled once with `load` and `uptime`, expecting `reboots` reboots
+pub async fn mock_ubus_router(server: &MockServer, load: f64, uptime: u64, reboots: u64) {
+    mock_login(server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info_with_uptime(load, uptime)))
+        .expect(1)
+        .mount(server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(reboots)
+        .mount(server)
+        .await;
+}
+
+/// Post-reboot verification is disabled unless a test enables it
+pub const NO_VERIFY: &str = "verify = { enabled = false }";
+
+/// Configuration of a single router named `mock` on `backend`, with `extra` lines in its table
+pub fn router_config(server: &MockServer, backend: &str, extra: &str) -> String {
+    format!(
+        r#"
+[[server]]
+name = "mock"
+host = "{}"
+user = "root"
+password = "password"
+backend = "{}"
+{}
+"#,
+        server.uri(),
+        backend,
+        extra
+    )
+}
+
+/// Run the binary once in `dir` with `config` as config.toml
+pub async fn run_config(dir: &Path, config: &str, args: &[&str]) -> Output {
+    run_config_env(dir, config, args, &[]).await
+}
+
+/// Like `run_config`, with `env` added to the environment of the binary
+pub async fn run_config_env(
+    dir: &Path,
+    config: &str,
+    args: &[&str],
+    env: &[(&str, String)],
+) -> Output {
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_openwrt-autoreboot"))
+        .args(args)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .current_dir(dir)
+        .output()
+        .await
+        .unwrap()
+}
+
+/// Start the binary in daemon mode in `dir` with `config` as config.toml, killed on drop
+pub fn spawn_daemon(dir: &Path, config: &str) -> tokio::process::Child {
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_openwrt-autoreboot"))
+        .arg("daemon")
+        .current_dir(dir)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+pub async fn run_in(dir: &Path, server: &MockServer, extra: &str, args: &[&str]) -> Output {
+    run_config(dir, &router_config(server, "ubus", extra), args).await
+}
+
+pub async fn run(server: &MockServer, args: &[&str]) -> Output {
+    let dir = tempfile::tempdir().unwrap();
+    run_in(dir.path(), server, NO_VERIFY, args).await
+}
+
+/// LuCI login response carrying a session cookie
+pub fn luci_session() -> ResponseTemplate {
+    ResponseTemplate::new(302)
+        .insert_header(
+            "Set-Cookie",
+            format!("sysauth={}; path=/cgi-bin/luci", SESSION).as_str(),
+        )
+        .insert_header("Location", "/cgi-bin/luci/")
+}
+
+/// Mock LuCI login, answered with a session cookie
+pub async fn mock_luci_login(server: &MockServer) {
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci"))
+        .respond_with(luci_session())
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+/// LuCI login form, rendered instead of a page when the session is not valid
+pub fn luci_login_page() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_string(
+        "<form><input name=\"luci_username\"><input name=\"luci_password\"></form>",
+    )
+}
+
+/// LuCI `?status=1` response with `load` on every load average
+pub fn luci_status(cpu: i32, load: f64) -> ResponseTemplate {
+    let load = (load * 65536.0) as u64;
+    ResponseTemplate::new(200).set_body_json(json!({
+        "cpuusage": cpu.to_string(),
+        "loadavg": [load, load, load],
+        "uptime": 3600,
+    }))
+}
+
+/// LuCI page carrying a CSRF token
+pub fn luci_page() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_string(format!(
+        "<script>L = new LuCI({{ token: '{}' }});</script>",
+        SESSION
+    ))
+}
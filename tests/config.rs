@@ -0,0 +1,80 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use wiremock::{MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn single_server_table_is_accepted() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&router, "ubus", NO_VERIFY).replace("[[server]]", "[server]");
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn duplicate_names_are_rejected() {
+    let router = MockServer::start().await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config =
+        router_config(&router, "ubus", NO_VERIFY) + &router_config(&router, "ubus", NO_VERIFY);
+    let output = run_config(dir.path(), &config, &[]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("set a unique name"));
+    assert!(router.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn failing_router_does_not_stop_others() {
+    let rejecting = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32002, "message": "Access denied"},
+        })))
+        .expect(1)
+        .mount(&rejecting)
+        .await;
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&rejecting, "ubus", NO_VERIFY).replace("\"mock\"", "\"rejecting\"")
+        + &router_config(&router, "ubus", NO_VERIFY);
+    assert_eq!(
+        run_config(dir.path(), &config, &[]).await.status.code(),
+        Some(2)
+    );
+}
+
+#[tokio::test]
+async fn host_is_required_except_for_local() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_config(dir.path(), "[[server]]\nbackend = \"ubus\"\n", &[]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("host is required"));
+}
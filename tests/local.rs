@@ -0,0 +1,157 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::{run_config_env, NO_VERIFY};
+use openwrt_autoreboot::procfs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Output;
+
+const MEMINFO: &str = "MemTotal:         124884 kB
+MemFree:           52432 kB
+MemAvailable:      61180 kB
+Buffers:            4096 kB
+Cached:            14232 kB
+Shmem:               580 kB
+";
+
+const STAT: &str = "cpu  1000 0 500 8000 500 0 0 0 0 0
+cpu0 1000 0 500 8000 500 0 0 0 0 0
+intr 12345
+";
+
+/// Write a fake procfs with `load` on every load average
+fn fake_proc(dir: &Path, load: f64) {
+    let proc_root = dir.join("proc");
+    std::fs::create_dir_all(proc_root.join("sys/net/netfilter")).unwrap();
+    std::fs::write(
+        proc_root.join("loadavg"),
+        format!("{0:.2} {0:.2} {0:.2} 1/64 1234\n", load),
+    )
+    .unwrap();
+    std::fs::write(proc_root.join("stat"), STAT).unwrap();
+    std::fs::write(proc_root.join("meminfo"), MEMINFO).unwrap();
+    std::fs::write(proc_root.join("uptime"), "3600.52 7000.10\n").unwrap();
+    std::fs::write(
+        proc_root.join("sys/net/netfilter/nf_conntrack_count"),
+        "42\n",
+    )
+    .unwrap();
+}
+
+/// Install a fake `ubus` command that logs its arguments
+fn fake_ubus(dir: &Path) {
+    let bin = dir.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    let script = bin.join("ubus");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\n",
+            dir.join("ubus.log").display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+async fn run_local(dir: &Path, extra: &str, args: &[&str]) -> Output {
+    let config = format!(
+        r#"
+[[server]]
+backend = "local"
+proc_root = "{}"
+{}
+{}
+"#,
+        dir.join("proc").display(),
+        NO_VERIFY,
+        extra
+    );
+    let path = format!(
+        "{}:{}",
+        dir.join("bin").display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    run_config_env(dir, &config, args, &[("PATH", path)]).await
+}
+
+fn ubus_log(dir: &Path) -> String {
+    std::fs::read_to_string(dir.join("ubus.log")).unwrap_or_default()
+}
+
+#[test]
+fn parse_procfs() {
+    let memory = procfs::parse_meminfo(MEMINFO).unwrap();
+    assert_eq!(memory.total, 124884 * 1024);
+    assert_eq!(memory.available, 61180 * 1024);
+    assert_eq!(memory.shared, 580 * 1024);
+    let without_available = MEMINFO.replace("MemAvailable:      61180 kB\n", "");
+    let memory = procfs::parse_meminfo(&without_available).unwrap();
+    assert_eq!(memory.available, (52432 + 4096 + 14232) * 1024);
+
+    let before = procfs::parse_stat(STAT).unwrap();
+    let after = procfs::parse_stat("cpu  1300 0 600 8100 500 0 0 0 0 0\n").unwrap();
+    assert_eq!(after.usage_since(&before), 80);
+
+    assert_eq!(procfs::parse_uptime("3600.52 7000.10\n").unwrap(), 3600);
+    assert!(procfs::parse_loadavg("0.10 0.20\n").is_err());
+}
+
+#[tokio::test]
+async fn reboot_when_overloaded() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_proc(dir.path(), 2.5);
+    fake_ubus(dir.path());
+    // Fake /proc/stat never changes, so cpu usage is 0
+    let rule = r#"rule = { all = [{ metric = "load1", above = 1.0 }, { metric = "conntrack", above = 10 }] }"#;
+    assert!(run_local(dir.path(), rule, &[]).await.status.success());
+    assert_eq!(ubus_log(dir.path()), "call system reboot\n");
+}
+
+#[tokio::test]
+async fn no_reboot_when_healthy() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_proc(dir.path(), 0.1);
+    fake_ubus(dir.path());
+    assert!(run_local(dir.path(), "", &[]).await.status.success());
+    assert_eq!(ubus_log(dir.path()), "");
+}
+
+#[tokio::test]
+async fn dry_run_checks_ubus() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_proc(dir.path(), 2.5);
+    fake_ubus(dir.path());
+    let rule = r#"rule = { metric = "load1", above = 1.0 }"#;
+    assert!(run_local(dir.path(), rule, &["--dry-run"])
+        .await
+        .status
+        .success());
+    assert_eq!(ubus_log(dir.path()), "list system\n");
+}
+
+#[tokio::test]
+async fn missing_procfs_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(run_local(dir.path(), "", &[]).await.status.code(), Some(4));
+}
@@ -0,0 +1,217 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn luci_config(server: &MockServer, extra: &str) -> String {
+    router_config(server, "luci", &format!("{}\n{}", NO_VERIFY, extra))
+}
+
+fn status_page() -> wiremock::MockBuilder {
+    Mock::given(method("GET")).and(path("/cgi-bin/luci/"))
+}
+
+const PING_LOST: &str = "PING 1.1.1.1 (1.1.1.1): 56 data bytes
+
+--- 1.1.1.1 ping statistics ---
+5 packets transmitted, 0 packets received, 100% packet loss
+";
+
+#[tokio::test]
+async fn router_ping_failure_reboots() {
+    // Target is percent-encoded in the diagnostic URL
+    for (host, target) in [
+        ("1.1.1.1", "1.1.1.1"),
+        ("fe80::1%br-lan", "fe80::1%25br-lan"),
+    ] {
+        let server = MockServer::start().await;
+        mock_luci_login(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/cgi-bin/luci/"))
+            .respond_with(luci_status(5, 0.1))
+            .expect(1)
+            .mount(&server)
+            .await;
+        for page in &["diagnostics", "reboot"] {
+            let section = if *page == "reboot" {
+                "system"
+            } else {
+                "network"
+            };
+            Mock::given(method("GET"))
+                .and(path(format!("/cgi-bin/luci/admin/{}/{}", section, page)))
+                .respond_with(luci_page())
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(path(format!(
+                "/cgi-bin/luci/admin/network/diag_ping/{}",
+                target
+            )))
+            .and(body_string_contains(SESSION))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PING_LOST))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/cgi-bin/luci/admin/system/reboot/call"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let extra = format!(
+            r#"probes = [{{ name = "upstream", type = "router_ping", host = "{}" }}]
+rule = {{ metric = "probe.upstream.loss", above = 50 }}"#,
+            host
+        );
+        let config = luci_config(&server, &extra);
+        assert!(run_config(dir.path(), &config, &[]).await.status.success());
+    }
+}
+
+#[tokio::test]
+async fn login_rejected() {
+    for response in [ResponseTemplate::new(403), luci_login_page()] {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/cgi-bin/luci"))
+            .respond_with(response)
+            .expect(1)
+            .mount(&server)
+            .await;
+        status_page()
+            .respond_with(luci_status(5, 0.1))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = run_config(dir.path(), &luci_config(&server, ""), &[]).await;
+        assert_eq!(output.status.code(), Some(2));
+    }
+}
+
+#[tokio::test]
+async fn expired_session_logs_in_again() {
+    for expired in [ResponseTemplate::new(403), luci_login_page()] {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/cgi-bin/luci"))
+            .respond_with(luci_session())
+            .expect(2)
+            .mount(&server)
+            .await;
+        status_page()
+            .respond_with(expired)
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        status_page()
+            .respond_with(luci_status(5, 0.1))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = run_config(dir.path(), &luci_config(&server, ""), &[]).await;
+        assert!(output.status.success());
+    }
+}
+
+#[tokio::test]
+async fn expired_session_on_reboot_page_logs_in_again() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci"))
+        .respond_with(luci_session())
+        .expect(2)
+        .mount(&server)
+        .await;
+    status_page()
+        .respond_with(luci_status(80, 2.5))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/cgi-bin/luci/admin/system/reboot"))
+        .respond_with(ResponseTemplate::new(403))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/cgi-bin/luci/admin/system/reboot"))
+        .respond_with(luci_page())
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci/admin/system/reboot/call"))
+        .and(body_string_contains(SESSION))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_config(dir.path(), &luci_config(&server, ""), &[]).await;
+    assert!(output.status.success());
+}
+
+#[tokio::test]
+async fn login_is_retried_with_backoff() {
+    let server = MockServer::start().await;
+    // First attempt times out
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci"))
+        .respond_with(luci_session().set_delay(std::time::Duration::from_secs(5)))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci"))
+        .respond_with(luci_session())
+        .expect(1)
+        .mount(&server)
+        .await;
+    status_page()
+        .respond_with(luci_status(5, 0.1))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let started = std::time::Instant::now();
+    let extra = "timeout = 1\nlogin_retry = { attempts = 2, backoff = 1 }";
+    let output = run_config(dir.path(), &luci_config(&server, extra), &[]).await;
+    assert!(output.status.success());
+    // One timeout and one backoff
+    assert!(started.elapsed().as_secs() >= 2);
+}
@@ -0,0 +1,134 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use chrono::{Datelike, TimeZone, Utc};
+use common::*;
+use openwrt_autoreboot::maintenance::MaintenanceWindow;
+use serde_json::json;
+use wiremock::matchers::method;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn window(config: &str) -> MaintenanceWindow {
+    toml::from_str(config).unwrap()
+}
+
+#[test]
+fn window_on_days() {
+    let window = window(
+        r#"days = ["sat", "sun"]
+start = "02:00"
+end = "05:00""#,
+    );
+    // 2021-07-03 is a saturday
+    assert!(window.contains(Utc.with_ymd_and_hms(2021, 7, 3, 3, 0, 0).unwrap()));
+    assert!(!window.contains(Utc.with_ymd_and_hms(2021, 7, 3, 5, 0, 0).unwrap()));
+    assert!(!window.contains(Utc.with_ymd_and_hms(2021, 7, 2, 3, 0, 0).unwrap()));
+}
+
+#[test]
+fn window_passes_midnight_in_timezone() {
+    let window = window(
+        r#"days = ["fri"]
+start = "23:00"
+end = "02:00"
+timezone = "Asia/Tokyo""#,
+    );
+    // Friday 23:30 and saturday 01:00 in Tokyo
+    assert!(window.contains(Utc.with_ymd_and_hms(2021, 7, 2, 14, 30, 0).unwrap()));
+    assert!(window.contains(Utc.with_ymd_and_hms(2021, 7, 2, 16, 0, 0).unwrap()));
+    // Saturday 23:30 in Tokyo
+    assert!(!window.contains(Utc.with_ymd_and_hms(2021, 7, 3, 14, 30, 0).unwrap()));
+}
+
+#[test]
+fn empty_window_is_rejected() {
+    let error = toml::from_str::<MaintenanceWindow>("start = \"03:00\"\nend = \"03:00\"")
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("never be open"), "{}", error);
+}
+
+/// Maintenance window that is closed for the whole day
+fn tomorrow_only() -> String {
+    let tomorrow = Utc::now().weekday().succ();
+    format!(
+        "[[maintenance]]\ndays = [\"{}\"]\nstart = \"00:00\"\nend = \"23:59\"\n",
+        tomorrow
+    )
+}
+
+#[tokio::test]
+async fn reboot_deferred_outside_window() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 0).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = tomorrow_only() + &router_config(&router, "ubus", NO_VERIFY);
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn emergency_overrides_window() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nemergency = {{ metric = \"load1\", above = 2.0 }}",
+        NO_VERIFY
+    );
+    let config = tomorrow_only() + &router_config(&router, "ubus", &extra);
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn deferred_reboot_is_alerted_once() {
+    let router = MockServer::start().await;
+    let hook = MockServer::start().await;
+    mock_login(&router).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(3..)
+        .mount(&router)
+        .await;
+    ubus_call(None, "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(0)
+        .mount(&router)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&hook)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = format!(
+        "interval = 1\njitter = 0\n{}{}\n[[notify.webhook]]\nurl = \"{}/hook\"\n",
+        tomorrow_only(),
+        router_config(&router, "ubus", NO_VERIFY),
+        hook.uri()
+    );
+    let daemon = spawn_daemon(dir.path(), &config);
+    tokio::time::sleep(std::time::Duration::from_millis(3500)).await;
+    drop(daemon);
+}
@@ -0,0 +1,101 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use openwrt_autoreboot::metrics::{self, Metrics};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wiremock::MockServer;
+
+#[tokio::test]
+async fn daemon_exports_metrics() {
+    let router = MockServer::start().await;
+    mock_login(&router).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info_with_uptime(0.5, 3600)))
+        .mount(&router)
+        .await;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let dir = tempfile::tempdir().unwrap();
+    let config = format!(
+        "metrics_listen = \"127.0.0.1:{}\"\n{}",
+        port,
+        router_config(&router, "ubus", NO_VERIFY)
+    );
+    let _daemon = spawn_daemon(dir.path(), &config);
+
+    let url = format!("http://127.0.0.1:{}/metrics", port);
+    let mut body = String::new();
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if let Ok(response) = reqwest::get(&url).await {
+            body = response.text().await.unwrap();
+            if body.contains("result=\"success\"} 1") {
+                break;
+            }
+        }
+    }
+    let labels = format!("router=\"mock\",host=\"{}\"", router.uri());
+    for line in &[
+        format!("openwrt_autoreboot_load1{{{}}} 0.5", labels),
+        format!("openwrt_autoreboot_uptime_seconds{{{}}} 3600", labels),
+        format!(
+            "openwrt_autoreboot_polls_total{{{},result=\"success\"}} 1",
+            labels
+        ),
+        format!(
+            "openwrt_autoreboot_polls_total{{{},result=\"failure\"}} 0",
+            labels
+        ),
+        format!("openwrt_autoreboot_reboots_issued_total{{{}}} 0", labels),
+    ] {
+        assert!(body.contains(line.as_str()), "{} not in\n{}", line, body);
+    }
+    assert!(!body.contains("cpu_usage_percent"));
+}
+
+#[tokio::test]
+async fn split_request_with_query_is_served() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let metrics = std::sync::Arc::new(Metrics::default());
+    metrics.register("mock", "http://mock");
+    metrics.record_reboot("mock");
+    tokio::spawn(metrics::serve(listener, metrics));
+
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(b"GET /metrics?x=1 HTTP/1.1\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    stream.write_all(b"Host: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("openwrt_autoreboot_reboots_issued_total"));
+}
@@ -0,0 +1,168 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use openwrt_autoreboot::probe::Probe;
+use serde_json::json;
+use tokio::net::UdpSocket;
+use wiremock::matchers::method;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn probe(config: &str) -> Probe {
+    toml::from_str(&format!("name = \"test\"\ntimeout = 2\n{}", config)).unwrap()
+}
+
+/// Answer one DNS query with `rcode` and `answers` records
+async fn fake_dns(rcode: u8, answers: u8) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 512];
+        let (size, peer) = socket.recv_from(&mut buffer).await.unwrap();
+        let mut response = buffer[..size].to_vec();
+        response[2] |= 0x80;
+        response[3] = rcode;
+        response[7] = answers;
+        socket.send_to(&response, peer).await.unwrap();
+    });
+    address
+}
+
+/// Socket that holds a port without listening, so connections to it are refused
+fn closed_socket() -> (tokio::net::TcpSocket, String) {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    (socket, address)
+}
+
+#[tokio::test]
+async fn dns_probe() {
+    let server = fake_dns(0, 1).await;
+    let config = format!(
+        "type = \"dns\"\nserver = \"{}\"\ndomain = \"openwrt.org\"",
+        server
+    );
+    assert!(probe(&config).run().await.is_ok());
+
+    let server = fake_dns(3, 0).await;
+    let config = format!(
+        "type = \"dns\"\nserver = \"{}\"\ndomain = \"openwrt.org\"",
+        server
+    );
+    assert!(probe(&config).run().await.is_err());
+}
+
+#[tokio::test]
+async fn tcp_probe() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = format!(
+        "type = \"tcp\"\naddress = \"{}\"",
+        listener.local_addr().unwrap()
+    );
+    assert!(probe(&config).run().await.is_ok());
+    let (_socket, closed) = closed_socket();
+    let config = format!("type = \"tcp\"\naddress = \"{}\"", closed);
+    assert!(probe(&config).run().await.is_err());
+}
+
+fn probe_config(web: &MockServer, local: &str) -> String {
+    format!(
+        r#"{}
+rule = {{ metric = "probe.failed", above = 0 }}
+probes = [
+    {{ name = "web", type = "http", url = "{}" }},
+    {{ name = "link", type = "tcp", address = "{}", local = true }},
+]"#,
+        NO_VERIFY,
+        web.uri(),
+        local
+    )
+}
+
+#[tokio::test]
+async fn failed_probe_reboots() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 0.1, 3600, 1).await;
+    let web = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&web)
+        .await;
+    // The router mock doubles as the local side
+    let local = router.address().to_string();
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = probe_config(&web, &local);
+    assert!(run_in(dir.path(), &router, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn failed_local_probe_ignores_probes() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 0.1, 3600, 0).await;
+    let web = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(0)
+        .mount(&web)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (_socket, closed) = closed_socket();
+    let extra = probe_config(&web, &closed);
+    assert!(run_in(dir.path(), &router, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn router_ping_through_ubus() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 0.1, 3600, 0).await;
+    ubus_call(Some(SESSION), "file", "exec")
+        .respond_with(result(json!([0, {
+            "code": 0,
+            "stdout": "5 packets transmitted, 5 packets received, 0% packet loss\n\
+                       round-trip min/avg/max = 10.1/12.5/15.0 ms\n",
+        }])))
+        .expect(1)
+        .mount(&router)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        r#"{}
+probes = [{{ name = "upstream", type = "router_ping", host = "1.1.1.1" }}]
+rule = {{ any = [{{ metric = "probe.upstream.loss", above = 50 }}, {{ metric = "probe.upstream.ms", above = 100 }}] }}"#,
+        NO_VERIFY
+    );
+    assert!(run_in(dir.path(), &router, &extra, &[])
+        .await
+        .status
+        .success());
+}
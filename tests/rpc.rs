@@ -0,0 +1,210 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use openwrt_autoreboot::procfs::SNAPSHOT_COMMAND;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::process::Output;
+use wiremock::matchers::{body_partial_json, method, path, query_param};
+use wiremock::{Mock, MockBuilder, MockServer, ResponseTemplate};
+
+const STAT: &str = "cpu  1000 0 500 8000 500 0 0 0 0 0\n";
+
+const MEMINFO: &str = "MemTotal:         124884 kB
+MemFree:           52432 kB
+MemAvailable:      61180 kB
+";
+
+/// Match a luci-mod-rpc call of `library.function` carrying the auth token
+fn rpc_call(library: &str, function: &str) -> MockBuilder {
+    Mock::given(method("POST"))
+        .and(path(format!("/cgi-bin/luci/rpc/{}", library)))
+        .and(query_param("auth", SESSION))
+        .and(body_partial_json(json!({ "method": function })))
+}
+
+fn exec(command: &str) -> MockBuilder {
+    rpc_call("sys", "exec").and(body_partial_json(json!({ "params": [command] })))
+}
+
+fn rpc_result(result: Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({"id": 1, "result": result, "error": null}))
+}
+
+/// Output of the procfs snapshot with `load` on every load average
+fn snapshot(load: f64) -> ResponseTemplate {
+    rpc_result(json!([
+        "cpu  1300 0 600 8100 500 0 0 0 0 0\n".to_string(),
+        format!("{0:.2} {0:.2} {0:.2} 1/64 1234\n", load),
+        MEMINFO.to_string(),
+        "3600.52 7000.10\n".to_string(),
+        "42\n".to_string(),
+    ]
+    .join("@@\n")))
+}
+
+async fn mock_rpc_login(server: &MockServer, times: u64) {
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci/rpc/auth"))
+        .and(body_partial_json(
+            json!({"method": "login", "params": ["root", "password"]}),
+        ))
+        .respond_with(rpc_result(json!(SESSION)))
+        .expect(times)
+        .mount(server)
+        .await;
+}
+
+async fn mock_status(server: &MockServer, load: f64) {
+    exec("cat /proc/stat")
+        .respond_with(rpc_result(json!(STAT)))
+        .mount(server)
+        .await;
+    exec(SNAPSHOT_COMMAND)
+        .respond_with(snapshot(load))
+        .mount(server)
+        .await;
+}
+
+async fn run_rpc(dir: &Path, server: &MockServer, args: &[&str]) -> Output {
+    let config = router_config(server, "rpc", NO_VERIFY);
+    run_config(dir, &config, args).await
+}
+
+#[tokio::test]
+async fn reboot_when_overloaded() {
+    let server = MockServer::start().await;
+    mock_rpc_login(&server, 1).await;
+    mock_status(&server, 2.5).await;
+    rpc_call("sys", "reboot")
+        .respond_with(rpc_result(json!(0)))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(run_rpc(dir.path(), &server, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn no_reboot_when_healthy() {
+    let server = MockServer::start().await;
+    mock_rpc_login(&server, 1).await;
+    mock_status(&server, 0.1).await;
+    rpc_call("sys", "reboot")
+        .respond_with(rpc_result(json!(0)))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(run_rpc(dir.path(), &server, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn login_rejected() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/cgi-bin/luci/rpc/auth"))
+        .respond_with(rpc_result(Value::Null))
+        .expect(1)
+        .mount(&server)
+        .await;
+    rpc_call("sys", "exec")
+        .respond_with(rpc_result(json!("")))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_rpc(dir.path(), &server, &[]).await;
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[tokio::test]
+async fn expired_token_logs_in_again() {
+    let server = MockServer::start().await;
+    mock_rpc_login(&server, 2).await;
+    exec("cat /proc/stat")
+        .respond_with(ResponseTemplate::new(403))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    mock_status(&server, 0.1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(run_rpc(dir.path(), &server, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn dry_run_never_reboots() {
+    let server = MockServer::start().await;
+    mock_rpc_login(&server, 1).await;
+    mock_status(&server, 2.5).await;
+    exec("command -v reboot 2>&1; echo \"@@$?\"")
+        .respond_with(rpc_result(json!("/sbin/reboot\n@@0\n")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    rpc_call("sys", "reboot")
+        .respond_with(rpc_result(json!(0)))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(run_rpc(dir.path(), &server, &["--dry-run"])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn remediation_runs_ubus_through_exec() {
+    let server = MockServer::start().await;
+    mock_rpc_login(&server, 1).await;
+    // Cpu counters do not advance, so the router is idle when polled again
+    mock_status(&server, 2.5).await;
+    exec(r#"ubus call rc init '{"action":"restart","name":"dnsmasq"}' 2>&1; echo "@@$?""#)
+        .respond_with(rpc_result(json!("@@0\n")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    rpc_call("sys", "reboot")
+        .respond_with(rpc_result(json!(0)))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(
+        &server,
+        "rpc",
+        &format!(
+            "{}\nremediation = {{ steps = [\"restart service dnsmasq\", \"reboot\"], delay = 0 }}",
+            NO_VERIFY
+        ),
+    );
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+}
@@ -0,0 +1,92 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use openwrt_autoreboot::config::Config;
+use openwrt_autoreboot::rule::Rule;
+use openwrt_autoreboot::status::Status;
+
+fn rule(config: &str) -> Result<Rule, toml::de::Error> {
+    toml::from_str(config)
+}
+
+fn status(cpu: i32) -> Status {
+    Status {
+        cpu_usage: Some(cpu),
+        load_avg: vec![0.1, 0.1, 0.1],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn misspelled_keys_are_rejected() {
+    assert!(rule("metric = \"cpu\"\nabvoe = 80").is_err());
+    assert!(rule("any = []\nabove = 80").is_err());
+    assert!(rule("not = { metric = \"cpu\", above = 80, below_ = 10 }").is_err());
+}
+
+#[test]
+fn rules_are_validated() {
+    assert!(rule("metric = \"cpu\"").unwrap().validate().is_err());
+    assert!(rule("metric = \"load_1\"\nabove = 2")
+        .unwrap()
+        .validate()
+        .is_err());
+    assert!(
+        rule("any = [{ metric = \"load1\", above = 2 }, { not = { metric = \"probe.wan\", above = 0 } }]")
+            .unwrap()
+            .validate()
+            .is_ok()
+    );
+}
+
+#[test]
+fn missing_metric_never_matches() {
+    let probe = rule("not = { metric = \"probe.wan\", above = 0 }").unwrap();
+    assert!(!probe.matches(&status(5)));
+    let mut reported = status(5);
+    reported.extra.insert("probe.wan".to_string(), 0.0);
+    assert!(probe.matches(&reported));
+
+    let any = rule("any = [{ metric = \"cpu\", above = 80 }, { not = { metric = \"conntrack\", below = 10 } }]")
+        .unwrap();
+    assert!(!any.matches(&status(5)));
+    assert!(any.matches(&status(90)));
+    assert!(!any.evaluate("test", &status(5), &mut Vec::new()));
+}
+
+#[tokio::test]
+async fn config_with_unknown_metric_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    let server = "[[server]]\nname = \"main\"\nhost = \"http://localhost\"\n";
+    std::fs::write(
+        &path,
+        format!("{}rule = {{ metric = \"cpu\", above = 80 }}", server),
+    )
+    .unwrap();
+    assert!(Config::load(&path).await.is_ok());
+    std::fs::write(
+        &path,
+        format!("{}emergency = {{ metric = \"load_1\", above = 4 }}", server),
+    )
+    .unwrap();
+    let error = Config::load(&path).await.err().unwrap().to_string();
+    assert!(error.contains("load_1"), "{}", error);
+}
@@ -0,0 +1,164 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::*;
+use openwrt_autoreboot::schedule::RebootSchedule;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use wiremock::matchers::method;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn timestamp(day: u32, hour: u32) -> u64 {
+    Utc.with_ymd_and_hms(2021, 7, day, hour, 0, 0)
+        .unwrap()
+        .timestamp() as u64
+}
+
+#[test]
+fn schedule_is_due_after_fire_time() {
+    let schedule: RebootSchedule = toml::from_str(
+        r#"cron = "0 4 * * Sun"
+timezone = "Europe/Berlin""#,
+    )
+    .unwrap();
+    // 2021-07-04 is a sunday, 04:00 in Berlin is 02:00 UTC
+    assert!(schedule.due(timestamp(3, 12), timestamp(4, 3)));
+    assert!(!schedule.due(timestamp(3, 12), timestamp(4, 1)));
+    assert!(!schedule.due(timestamp(4, 3), timestamp(5, 3)));
+}
+
+#[test]
+fn weekdays_are_numbered_like_crontab() {
+    let schedule = |cron: &str| {
+        toml::from_str::<RebootSchedule>(&format!("cron = \"{}\"", cron)).map_err(|e| e.to_string())
+    };
+    // 2021-07-04 is a sunday, 2021-07-05 a monday
+    let monday = schedule("0 4 * * 1").unwrap();
+    assert!(!monday.due(timestamp(3, 12), timestamp(4, 5)));
+    assert!(monday.due(timestamp(4, 12), timestamp(5, 5)));
+    for sunday in ["0 4 * * 0", "0 4 * * 7", "0 4 * * 6-7", "0 4 * * */7"] {
+        let sunday = schedule(sunday).unwrap();
+        assert!(sunday.due(timestamp(3, 12), timestamp(4, 5)));
+        assert!(!sunday.due(timestamp(4, 12), timestamp(5, 5)));
+    }
+    let weekdays = schedule("0 4 * * 1-5").unwrap();
+    assert!(!weekdays.due(timestamp(3, 12), timestamp(4, 5)));
+    assert!(weekdays.due(timestamp(4, 12), timestamp(5, 5)));
+    assert!(schedule("0 4 * * 8").is_err());
+}
+
+#[tokio::test]
+async fn held_scheduled_reboot_stays_due() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let checked = now - 2 * 60 * 60;
+    // Blocked by cooldown, then skipped by dry run
+    let cases = [
+        (
+            json!({"schedule_checked": checked, "reboots": [now - 60]}),
+            vec![],
+        ),
+        (json!({"schedule_checked": checked}), vec!["--dry-run"]),
+    ];
+    for (state, args) in cases {
+        let router = MockServer::start().await;
+        mock_ubus_router(&router, 0.1, 3600, 0).await;
+        ubus_call(Some(SESSION), "session", "access")
+            .respond_with(result(json!([0, {"access": true}])))
+            .mount(&router)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, json!({"routers": {"mock": state}}).to_string()).unwrap();
+        let extra = format!("{}\nschedule = {{ cron = \"0 * * * *\" }}", NO_VERIFY);
+        assert!(run_in(dir.path(), &router, &extra, &args)
+            .await
+            .status
+            .success());
+        let state: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(state["routers"]["mock"]["schedule_checked"], checked);
+    }
+}
+
+#[tokio::test]
+async fn reboot_on_max_uptime() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 0.1, 3 * 24 * 60 * 60, 1).await;
+
+    let hook = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&hook)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!("{}\nmax_uptime_days = 2", NO_VERIFY);
+    let config = router_config(&router, "ubus", &extra)
+        + &format!("\n[[notify.webhook]]\nurl = \"{}/hook\"\n", hook.uri());
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+    let events: Vec<Value> = hook
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice::<Value>(&request.body).unwrap()["event"].clone())
+        .collect();
+    assert_eq!(events, [json!("preventive_reboot"), json!("reboot_issued")]);
+}
+
+#[tokio::test]
+async fn first_poll_starts_schedule() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 0.1, 3600, 0).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!("{}\nschedule = {{ cron = \"* * * * *\" }}", NO_VERIFY);
+    assert!(run_in(dir.path(), &router, &extra, &[])
+        .await
+        .status
+        .success());
+    let state = std::fs::read_to_string(dir.path().join("state.json")).unwrap();
+    let state: Value = serde_json::from_str(&state).unwrap();
+    assert!(state["routers"]["mock"]["schedule_checked"].is_u64());
+}
+
+#[tokio::test]
+async fn scheduled_reboot() {
+    let router = MockServer::start().await;
+    mock_ubus_router(&router, 0.1, 3600, 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("state.json"),
+        json!({"routers": {"mock": {"schedule_checked": timestamp(3, 12)}}}).to_string(),
+    )
+    .unwrap();
+    let extra = format!("{}\nschedule = {{ cron = \"0 4 * * *\" }}", NO_VERIFY);
+    assert!(run_in(dir.path(), &router, &extra, &[])
+        .await
+        .status
+        .success());
+}
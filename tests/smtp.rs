@@ -0,0 +1,146 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use openwrt_autoreboot::notify::smtp::{SmtpConfig, SmtpTls};
+use openwrt_autoreboot::notify::{Event, Notification, Notifier, Smtp};
+use openwrt_autoreboot::status::{Memory, Status};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// What the SMTP sink received in one session
+#[derive(Default)]
+struct Received {
+    auth: Option<String>,
+    recipients: Vec<String>,
+    data: String,
+}
+
+/// Accept a single SMTP session on a local port and record it
+async fn smtp_sink() -> (u16, JoinHandle<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut received = Received::default();
+        writer
+            .write_all(b"220 localhost ESMTP sink\r\n")
+            .await
+            .unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let command = line.to_ascii_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") {
+                b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+            } else if command.starts_with("AUTH") {
+                received.auth = Some(line.clone());
+                b"235 2.7.0 Authentication successful\r\n"
+            } else if command.starts_with("RCPT TO") {
+                received.recipients.push(line[8..].to_string());
+                b"250 OK\r\n"
+            } else if command.starts_with("DATA") {
+                writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    if line == "." {
+                        break;
+                    }
+                    received.data.push_str(&line);
+                    received.data.push('\n');
+                }
+                b"250 OK\r\n"
+            } else if command.starts_with("QUIT") {
+                writer.write_all(b"221 Bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 OK\r\n"
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+        received
+    });
+    (port, handle)
+}
+
+fn config(port: u16) -> SmtpConfig {
+    SmtpConfig {
+        host: "127.0.0.1".to_string(),
+        port: Some(port),
+        tls: SmtpTls::None,
+        username: Some("monitor".to_string()),
+        password: Some("secret".to_string()),
+        from: "Auto reboot <autoreboot@example.com>".to_string(),
+        to: vec![
+            "oncall@example.com".to_string(),
+            "noc@example.com".to_string(),
+        ],
+        events: Vec::new(),
+    }
+}
+
+fn notification() -> Notification {
+    let status = Status {
+        cpu_usage: Some(35),
+        load_avg: vec![2.5, 1.5, 1.0],
+        memory: Some(Memory {
+            total: 128 * 1024 * 1024,
+            available: 16 * 1024 * 1024,
+            ..Default::default()
+        }),
+        uptime: Some(86400),
+        ..Default::default()
+    };
+    Notification::new(
+        Event::RebootIssued,
+        "office",
+        "http://192.168.1.1",
+        Some(&status),
+        "cpu usage 35% > 20%, load1 2.50 > 1.00".to_string(),
+    )
+}
+
+#[tokio::test]
+async fn email_contains_metrics() {
+    let (port, sink) = smtp_sink().await;
+    let smtp = Smtp::new(config(port)).unwrap();
+    smtp.send(&notification()).await.unwrap();
+
+    let received = sink.await.unwrap();
+    assert!(received.auth.is_some());
+    assert_eq!(
+        received.recipients,
+        vec!["<oncall@example.com>", "<noc@example.com>"]
+    );
+    assert!(received
+        .data
+        .contains("Subject: [openwrt-autoreboot] office: reboot issued"));
+    assert!(received.data.contains("Host: http://192.168.1.1"));
+    assert!(received.data.contains("CPU usage: 35%"));
+    assert!(received.data.contains("Load average: 2.50 1.50 1.00"));
+}
+
+#[test]
+fn events_are_filtered() {
+    let mut config = config(25);
+    config.events = vec![Event::RebootFailed];
+    let smtp = Smtp::new(config).unwrap();
+    assert!(smtp.accepts(Event::RebootFailed));
+    assert!(!smtp.accepts(Event::ThresholdCrossed));
+}
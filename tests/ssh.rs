@@ -0,0 +1,267 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use async_trait::async_trait;
+use common::{run_config, NO_VERIFY};
+use russh::server::{self, Auth, Msg, Server as _, Session};
+use russh::{Channel, ChannelId, CryptoVec};
+use russh_keys::key::{KeyPair, PublicKey};
+use russh_keys::PublicKeyBase64;
+use std::path::Path;
+use std::process::Output;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const PASSWORD: &str = "secret";
+
+const STAT: &str = "cpu  1000 0 500 8000 500 0 0 0 0 0\n";
+
+const MEMINFO: &str = "MemTotal:         124884 kB
+MemFree:           52432 kB
+MemAvailable:      61180 kB
+";
+
+/// SSH server answering the commands of the ssh backend
+#[derive(Clone)]
+struct FakeRouter {
+    load: f64,
+    client_key: Option<PublicKey>,
+    commands: Arc<Mutex<Vec<String>>>,
+    /// Drops the connection on reboot and comes back healthy
+    rebooted: Arc<AtomicBool>,
+}
+
+impl FakeRouter {
+    fn new(load: f64) -> Self {
+        Self {
+            load,
+            client_key: None,
+            commands: Default::default(),
+            rebooted: Default::default(),
+        }
+    }
+
+    fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    fn respond(&self, command: &str) -> String {
+        let (load, uptime) = if self.rebooted.load(Ordering::SeqCst) {
+            (0.1, 60)
+        } else {
+            (self.load, 3600)
+        };
+        match command {
+            "cat /proc/stat" => STAT.to_string(),
+            "command -v reboot" => "/sbin/reboot\n".to_string(),
+            "reboot" => String::new(),
+            _ => [
+                "cpu  1300 0 600 8100 500 0 0 0 0 0\n".to_string(),
+                format!("{0:.2} {0:.2} {0:.2} 1/64 1234\n", load),
+                MEMINFO.to_string(),
+                format!("{}.52 7000.10\n", uptime),
+                "42\n".to_string(),
+            ]
+            .join("@@\n"),
+        }
+    }
+
+    /// Serve on a random port, return the port
+    async fn start(&self, host_key: KeyPair) -> u16 {
+        let config = Arc::new(server::Config {
+            keys: vec![host_key],
+            auth_rejection_time: Duration::from_millis(10),
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut router = self.clone();
+        tokio::spawn(async move { router.run_on_socket(config, &listener).await });
+        port
+    }
+}
+
+impl server::Server for FakeRouter {
+    type Handler = Self;
+
+    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
+        self.clone()
+    }
+}
+
+#[async_trait]
+impl server::Handler for FakeRouter {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        Ok(match (user, password) {
+            ("root", PASSWORD) => Auth::Accept,
+            _ => Auth::Reject {
+                proceed_with_methods: None,
+            },
+        })
+    }
+
+    async fn auth_publickey(&mut self, _: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        Ok(match &self.client_key {
+            Some(client_key) if client_key == key => Auth::Accept,
+            _ => Auth::Reject {
+                proceed_with_methods: None,
+            },
+        })
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        _: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data).to_string();
+        let output = self.respond(&command);
+        self.commands.lock().unwrap().push(command.clone());
+        if command == "reboot" {
+            self.rebooted.store(true, Ordering::SeqCst);
+            return Err(russh::Error::Disconnect);
+        }
+        session.channel_success(channel);
+        session.data(channel, CryptoVec::from(output));
+        session.exit_status_request(channel, 0);
+        session.eof(channel);
+        session.close(channel);
+        Ok(())
+    }
+}
+
+fn known_hosts(dir: &Path, port: u16, key: &KeyPair) {
+    let key = key.clone_public_key().unwrap();
+    std::fs::write(
+        dir.join("known_hosts"),
+        format!(
+            "[127.0.0.1]:{} {} {}\n",
+            port,
+            key.name(),
+            key.public_key_base64()
+        ),
+    )
+    .unwrap();
+}
+
+async fn run_ssh(dir: &Path, port: u16, extra: &str) -> Output {
+    let config = format!(
+        r#"
+[[server]]
+name = "ssh"
+host = "127.0.0.1"
+backend = "ssh"
+ssh_port = {}
+known_hosts = "{}"
+{}
+"#,
+        port,
+        dir.join("known_hosts").display(),
+        extra
+    );
+    run_config(dir, &config, &[]).await
+}
+
+#[tokio::test]
+async fn reboot_over_ssh_with_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let host_key = KeyPair::generate_ed25519().unwrap();
+    let router = FakeRouter::new(2.5);
+    let port = router.start(host_key.clone()).await;
+    known_hosts(dir.path(), port, &host_key);
+
+    let extra = format!("password = \"{}\"\n{}", PASSWORD, NO_VERIFY);
+    assert!(run_ssh(dir.path(), port, &extra).await.status.success());
+    let commands = router.commands();
+    assert_eq!(commands.first().unwrap(), "cat /proc/stat");
+    assert_eq!(commands.last().unwrap(), "reboot");
+}
+
+#[tokio::test]
+async fn healthy_over_ssh_with_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let host_key = KeyPair::generate_ed25519().unwrap();
+    let client_key = KeyPair::generate_ed25519().unwrap();
+    let key_file = std::fs::File::create(dir.path().join("id_ed25519")).unwrap();
+    russh_keys::encode_pkcs8_pem(&client_key, key_file).unwrap();
+    let mut router = FakeRouter::new(0.1);
+    router.client_key = Some(client_key.clone_public_key().unwrap());
+    let port = router.start(host_key.clone()).await;
+    known_hosts(dir.path(), port, &host_key);
+
+    let extra = format!(
+        "ssh_key = \"{}\"\n{}",
+        dir.path().join("id_ed25519").display(),
+        NO_VERIFY
+    );
+    assert!(run_ssh(dir.path(), port, &extra).await.status.success());
+    let commands = router.commands();
+    assert_eq!(commands.len(), 2);
+    assert!(!commands.contains(&"reboot".to_string()));
+}
+
+#[tokio::test]
+async fn changed_host_key_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let router = FakeRouter::new(2.5);
+    let port = router.start(KeyPair::generate_ed25519().unwrap()).await;
+    known_hosts(dir.path(), port, &KeyPair::generate_ed25519().unwrap());
+
+    let extra = format!("password = \"{}\"\n{}", PASSWORD, NO_VERIFY);
+    let output = run_ssh(dir.path(), port, &extra).await;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(router.commands().is_empty());
+}
+
+#[tokio::test]
+async fn reboot_over_ssh_is_verified() {
+    let dir = tempfile::tempdir().unwrap();
+    let host_key = KeyPair::generate_ed25519().unwrap();
+    let router = FakeRouter::new(2.5);
+    let port = router.start(host_key.clone()).await;
+    known_hosts(dir.path(), port, &host_key);
+
+    let extra = format!(
+        "password = \"{}\"\nverify = {{ interval = 1, down_timeout = 5, up_timeout = 5 }}",
+        PASSWORD
+    );
+    assert!(run_ssh(dir.path(), port, &extra).await.status.success());
+    let state = std::fs::read_to_string(dir.path().join("state.json")).unwrap();
+    let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+    assert_eq!(
+        state["routers"]["ssh"]["last_verification"]["outcome"],
+        "success"
+    );
+}
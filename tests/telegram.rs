@@ -0,0 +1,118 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn mock_send_message(api: &MockServer, token: &str, times: u64) {
+    Mock::given(method("POST"))
+        .and(path(format!("/bot{}/sendMessage", token)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"ok": true})))
+        .expect(times)
+        .mount(api)
+        .await;
+}
+
+async fn sent_messages(api: &MockServer) -> Vec<Value> {
+    api.received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn reboot_is_sent_to_chat() {
+    let router = MockServer::start().await;
+    let api = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+    mock_send_message(&api, "123:abc", 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&router, "ubus", NO_VERIFY)
+        + &format!(
+            r#"
+[notify.telegram]
+token = "123:abc"
+chat_id = "42"
+api_url = "{}"
+template = "{{router}} {{event}}: load {{load}}"
+events = ["reboot_issued"]
+"#,
+            api.uri()
+        );
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+
+    let messages = sent_messages(&api).await;
+    assert_eq!(messages[0]["chat_id"], "42");
+    assert_eq!(
+        messages[0]["text"],
+        "mock reboot issued: load 2.50 2.50 2.50"
+    );
+}
+
+#[tokio::test]
+async fn server_overrides_global_chat() {
+    let router = MockServer::start().await;
+    let api = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+    mock_send_message(&api, "global", 2).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!("{}\ntelegram = {{ chat_id = \"7\" }}", NO_VERIFY);
+    let config = router_config(&router, "ubus", &extra)
+        + &format!(
+            "\n[notify.telegram]\ntoken = \"global\"\nchat_id = \"42\"\napi_url = \"{}\"\n",
+            api.uri()
+        );
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+
+    let messages = sent_messages(&api).await;
+    assert!(messages.iter().all(|message| message["chat_id"] == "7"));
+}
+
+#[tokio::test]
+async fn token_is_not_logged() {
+    let router = MockServer::start().await;
+    let api = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(502).set_body_string("<html>Bad Gateway</html>"))
+        .expect(1)
+        .mount(&api)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&router, "ubus", NO_VERIFY)
+        + &format!(
+            "\n[notify.telegram]\ntoken = \"123:secret\"\nchat_id = \"42\"\napi_url = \"{}\"\nevents = [\"reboot_issued\"]\n",
+            api.uri()
+        );
+    let output = run_config(dir.path(), &config, &[]).await;
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Unable to send"), "{}", log);
+    assert!(!log.contains("123:secret"), "{}", log);
+}
@@ -0,0 +1,600 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+use wiremock::matchers::body_partial_json;
+use wiremock::{MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn reboot_when_overloaded() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    assert!(run(&server, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn no_reboot_when_healthy() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(None, "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    assert!(run(&server, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn login_rejected() {
+    let server = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32002, "message": "Access denied"},
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(None, "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    assert_eq!(run(&server, &[]).await.status.code(), Some(2));
+}
+
+#[tokio::test]
+async fn reboot_denied_is_failure() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([6])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    assert_eq!(run(&server, &[]).await.status.code(), Some(6));
+}
+
+#[tokio::test]
+async fn dry_run_never_reboots() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "session", "access")
+        .respond_with(result(json!([0, {"access": true}])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(None, "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    assert!(run(&server, &["--dry-run"]).await.status.success());
+}
+
+#[tokio::test]
+async fn malformed_status_is_reported() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(json!([0, {"uptime": "unknown"}])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    assert_eq!(run(&server, &[]).await.status.code(), Some(4));
+}
+
+#[tokio::test]
+async fn cooldown_blocks_second_reboot() {
+    let server = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(result(json!([0, {"ubus_rpc_session": SESSION}])))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(run_in(dir.path(), &server, NO_VERIFY, &[])
+        .await
+        .status
+        .success());
+    assert!(dir.path().join("state.json").exists());
+    assert!(run_in(dir.path(), &server, NO_VERIFY, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn reboot_is_verified() {
+    let server = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(result(json!([0, {"ubus_rpc_session": SESSION}])))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // Router is down once, then comes back with reset uptime
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(ResponseTemplate::new(502))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info_with_uptime(0.1, 20)))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let verify = "verify = { interval = 1, down_timeout = 5, up_timeout = 5 }";
+    assert!(run_in(dir.path(), &server, verify, &[])
+        .await
+        .status
+        .success());
+    let state = std::fs::read_to_string(dir.path().join("state.json")).unwrap();
+    let state: Value = serde_json::from_str(&state).unwrap();
+    assert_eq!(
+        state["routers"]["mock"]["last_verification"]["outcome"],
+        "success"
+    );
+}
+
+#[tokio::test]
+async fn window_spans_runs() {
+    let server = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(result(json!([0, {"ubus_rpc_session": SESSION}])))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // Each run adds one sample, the second one reboots
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!("{}\nwindow = {{ samples = 2, required = 2 }}", NO_VERIFY);
+    for _ in 0..2 {
+        assert!(run_in(dir.path(), &server, &extra, &[])
+            .await
+            .status
+            .success());
+    }
+    let reboots = server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|request| serde_json::from_slice::<Value>(&request.body).unwrap())
+        .position(|body| body["params"][2] == "reboot");
+    // login and info of both runs come first
+    assert_eq!(reboots, Some(4));
+}
+
+#[tokio::test]
+async fn reboot_on_memory_pressure() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    // 70 of 128 MiB available, load is low
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nthreshold = {{ memory = {{ available_mb = 100 }} }}",
+        NO_VERIFY
+    );
+    assert!(run_in(dir.path(), &server, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn missing_available_memory_falls_back_to_free() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    // 64 MiB free and 4 MiB buffered, no available field
+    let mut info = system_info(0.1);
+    info[1]["memory"]
+        .as_object_mut()
+        .unwrap()
+        .remove("available");
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(info))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(None, "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nrule = {{ metric = \"mem_available\", below = 60 }}",
+        NO_VERIFY
+    );
+    assert!(run_in(dir.path(), &server, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn memory_rule_combined_with_load() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(None, "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nthreshold = {{ memory = {{ available_percent = 60.0, mode = \"all\" }} }}",
+        NO_VERIFY
+    );
+    assert!(run_in(dir.path(), &server, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn rule_decides_reboot() {
+    let server = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(result(json!([0, {"ubus_rpc_session": SESSION}])))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)))
+        .expect(2)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // 70 MiB available and 3600s uptime
+    let low_memory = r#"rule = { any = [
+        { metric = "load1", above = 2.0 },
+        { not = { metric = "mem_available", above = 100 } },
+    ] }"#;
+    let recently_booted = r#"rule = { all = [
+        { metric = "mem_available", below = 100 },
+        { metric = "uptime", below = 600 },
+    ] }"#;
+    for rule in &[recently_booted, low_memory] {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = format!("{}\n{}", NO_VERIFY, rule);
+        assert!(run_in(dir.path(), &server, &extra, &[])
+            .await
+            .status
+            .success());
+    }
+}
+
+#[tokio::test]
+async fn remediation_fixes_router() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "rc", "init")
+        .and(body_partial_json(json!({
+            "params": [SESSION, "rc", "init", {"name": "dnsmasq", "action": "restart"}],
+        })))
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(None, "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nremediation = {{ steps = [\"restart service dnsmasq\", \"reboot\"], delay = 0 }}",
+        NO_VERIFY
+    );
+    assert!(run_in(dir.path(), &server, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn remediation_escalates_to_reboot() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(3)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "rc", "init")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "file", "exec")
+        .and(body_partial_json(json!({
+            "params": [SESSION, "file", "exec", {"command": "/sbin/wifi", "params": ["reload"]}],
+        })))
+        .respond_with(result(json!([0, {"code": 0}])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nremediation = {{ steps = [\"restart service dnsmasq\", \"wifi reload\", \"reboot\"], delay = 0 }}",
+        NO_VERIFY
+    );
+    assert!(run_in(dir.path(), &server, &extra, &[])
+        .await
+        .status
+        .success());
+}
+
+#[tokio::test]
+async fn failed_remediation_command_escalates() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    // Not polled again, the failed step escalates right away
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "file", "exec")
+        .respond_with(result(json!([0, {"code": 1, "stderr": "wifi: not found"}])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let extra = format!(
+        "{}\nremediation = {{ steps = [\"wifi reload\", \"reboot\"], delay = 0 }}",
+        NO_VERIFY
+    );
+    let output = run_in(dir.path(), &server, &extra, &[]).await;
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("wifi: not found"), "{}", log);
+}
+
+#[tokio::test]
+async fn unresponsive_router_times_out() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)).set_delay(std::time::Duration::from_secs(30)))
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let started = std::time::Instant::now();
+    let extra = format!("{}\ntimeout = 1", NO_VERIFY);
+    let output = run_in(dir.path(), &server, &extra, &[]).await;
+    assert_eq!(output.status.code(), Some(3));
+    assert!(started.elapsed().as_secs() < 10);
+}
+
+#[tokio::test]
+async fn stuck_check_is_abandoned() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "rc", "init")
+        .respond_with(result(json!([0])))
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let started = std::time::Instant::now();
+    let extra = format!(
+        "{}\nremediation = {{ steps = [\"restart service dnsmasq\", \"reboot\"], delay = 60 }}",
+        NO_VERIFY
+    );
+    let config = format!(
+        "check_timeout = 1\n{}",
+        router_config(&server, "ubus", &extra)
+    );
+    let output = run_config(dir.path(), &config, &[]).await;
+    assert_eq!(output.status.code(), Some(3));
+    assert!(started.elapsed().as_secs() < 10);
+}
+
+#[tokio::test]
+async fn verification_gives_up_on_silent_router() {
+    let server = MockServer::start().await;
+    ubus_call(None, "session", "login")
+        .respond_with(result(json!([0, {"ubus_rpc_session": SESSION}])))
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(2.5)))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    ubus_call(Some(SESSION), "system", "reboot")
+        .respond_with(result(json!([0])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // Router accepts connections but never answers again
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)).set_delay(std::time::Duration::from_secs(60)))
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let started = std::time::Instant::now();
+    let verify = "timeout = 60\nverify = { interval = 1, down_timeout = 2, up_timeout = 2 }";
+    run_in(dir.path(), &server, verify, &[]).await;
+    assert!(started.elapsed().as_secs() < 15);
+    let state = std::fs::read_to_string(dir.path().join("state.json")).unwrap();
+    let state: Value = serde_json::from_str(&state).unwrap();
+    assert_eq!(
+        state["routers"]["mock"]["last_verification"]["outcome"],
+        "did_not_return"
+    );
+}
+
+#[tokio::test]
+async fn daemon_stops_on_sigterm() {
+    let server = MockServer::start().await;
+    mock_login(&server).await;
+    ubus_call(Some(SESSION), "system", "info")
+        .respond_with(result(system_info(0.1)))
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = format!(
+        "interval = 1\njitter = 0\n{}",
+        router_config(&server, "ubus", NO_VERIFY)
+    );
+    let mut daemon = spawn_daemon(dir.path(), &config);
+    // Wait for the first poll, so the signal handler is installed
+    for _ in 0..50 {
+        if server.received_requests().await.unwrap().len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let pid = daemon.id().unwrap() as libc::pid_t;
+    // SAFETY: kill takes no pointers, pid is our own child
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+    let status = tokio::time::timeout(std::time::Duration::from_secs(10), daemon.wait())
+        .await
+        .expect("daemon did not stop")
+        .unwrap();
+    assert_eq!(status.code(), Some(0));
+}
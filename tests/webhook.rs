@@ -0,0 +1,131 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of openwrt-autoreboot and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request};
+
+/// Match a webhook notification by event
+struct EventIs(&'static str);
+
+impl wiremock::Match for EventIs {
+    fn matches(&self, request: &Request) -> bool {
+        serde_json::from_slice::<Value>(&request.body)
+            .map(|body| body["event"] == self.0)
+            .unwrap_or(false)
+    }
+}
+
+async fn mock_event(hook: &MockServer, event: &'static str, times: u64) {
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(EventIs(event))
+        .respond_with(wiremock::ResponseTemplate::new(204))
+        .expect(times)
+        .mount(hook)
+        .await;
+}
+
+fn webhook_config(hook: &MockServer, events: &str) -> String {
+    format!(
+        "\n[[notify.webhook]]\nurl = \"{}/hook\"\nevents = [{}]\n",
+        hook.uri(),
+        events
+    )
+}
+
+#[tokio::test]
+async fn reboot_is_notified() {
+    let router = MockServer::start().await;
+    let hook = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+    mock_event(&hook, "threshold_crossed", 1).await;
+    mock_event(&hook, "reboot_issued", 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&router, "ubus", NO_VERIFY) + &webhook_config(&hook, "");
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+
+    let requests = hook.received_requests().await.unwrap();
+    let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["router"], "mock");
+    assert_eq!(body["metrics"]["load_avg"], json!([2.5, 2.5, 2.5]));
+    assert!(body["reason"]
+        .as_str()
+        .unwrap()
+        .contains("load1 2.50 > 1.00"));
+}
+
+#[tokio::test]
+async fn events_are_filtered() {
+    let router = MockServer::start().await;
+    let hook = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+    mock_event(&hook, "threshold_crossed", 0).await;
+    mock_event(&hook, "reboot_issued", 1).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config =
+        router_config(&router, "ubus", NO_VERIFY) + &webhook_config(&hook, "\"reboot_issued\"");
+    assert!(run_config(dir.path(), &config, &[]).await.status.success());
+}
+
+#[tokio::test]
+async fn unreachable_router_is_notified() {
+    let hook = MockServer::start().await;
+    mock_event(&hook, "router_unreachable", 1).await;
+    let router = MockServer::start().await;
+    mock_login(&router).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&router, "ubus", NO_VERIFY) + &webhook_config(&hook, "");
+    // No system.info mock, the router answers 404
+    assert_eq!(
+        run_config(dir.path(), &config, &[]).await.status.code(),
+        Some(3)
+    );
+}
+
+#[tokio::test]
+async fn url_is_not_logged() {
+    let router = MockServer::start().await;
+    let hook = MockServer::start().await;
+    mock_ubus_router(&router, 2.5, 3600, 1).await;
+    Mock::given(method("POST"))
+        .respond_with(wiremock::ResponseTemplate::new(500))
+        .expect(2)
+        .mount(&hook)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = router_config(&router, "ubus", NO_VERIFY)
+        + &format!(
+            "\n[[notify.webhook]]\nurl = \"{}/services/T000/B000/secret\"\n",
+            hook.uri()
+        );
+    let output = run_config(dir.path(), &config, &[]).await;
+    assert!(output.status.success());
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Unable to send"), "{}", log);
+    assert!(!log.contains("secret"), "{}", log);
+}